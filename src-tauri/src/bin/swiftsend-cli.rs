@@ -0,0 +1,3 @@
+fn main() -> std::process::ExitCode {
+    sendme_desktop_lib::run_cli()
+}
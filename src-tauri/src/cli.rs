@@ -0,0 +1,337 @@
+//! The `swiftsend-cli` binary, for machines without a desktop.
+//!
+//! Runs the same shares and receives as the app and keeps its history in the
+//! same place, but reports progress in the terminal.
+//!
+//! The binary can't be called `swiftsend`, that is the name of the app
+//! binary of the same package.
+
+use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
+use console::style;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+
+use crate::error::SwiftsendError;
+use crate::export::{ConflictPolicy, ExportAction};
+use crate::history::{self, HistoryEntry};
+use crate::iroh_send::{self, Network, ReceiveOptions, TransferContext};
+use crate::logging;
+use crate::manifest::Selection;
+use crate::progress::{
+    FileProgress, ImportProgressUpdate, ProgressPhase, ProgressSink, ProgressUpdate, Reconnecting,
+};
+use crate::transfers::{self, CancelHandle};
+
+/// Send files and directories between machines, using blake3 verified streaming.
+#[derive(Debug, Parser)]
+#[command(name = "swiftsend-cli", version, about)]
+struct Cli {
+    /// Log more. `SWIFTSEND_LOG` or `RUST_LOG` take precedence.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Share files and directories until interrupted, and print the ticket.
+    Send {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Receive a ticket into a directory, the current one by default.
+    Receive(ReceiveArgs),
+    /// List the files of a ticket without receiving them.
+    Inspect {
+        ticket: String,
+        /// Print the manifest as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the shares in the history, the most recent first.
+    Shares {
+        /// Also print the ticket of every share.
+        #[arg(long)]
+        tickets: bool,
+    },
+}
+
+#[derive(Debug, Args)]
+struct ReceiveArgs {
+    ticket: String,
+    dir: Option<PathBuf>,
+    /// Bandwidth limit in bytes per second.
+    #[arg(long)]
+    limit: Option<u64>,
+    /// What to do with files that already exist [default: fail]
+    #[arg(long, value_enum)]
+    conflict: Option<ConflictPolicy>,
+    /// Only receive the entries matching a name, directory or glob pattern.
+    #[arg(long = "select", value_name = "PATTERN")]
+    select: Vec<String>,
+}
+
+/// Draws the progress of the single transfer the CLI runs.
+struct TerminalProgress {
+    bar: ProgressBar,
+}
+
+impl TerminalProgress {
+    fn new() -> Self {
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{prefix} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {msg}",
+            )
+            .expect("valid progress template")
+            .progress_chars("#>-"),
+        );
+        Self { bar }
+    }
+
+    fn show(
+        &self,
+        step: &str,
+        label: &str,
+        progress: u64,
+        total: u64,
+        rate: u64,
+        file: Option<&FileProgress>,
+    ) {
+        self.bar
+            .set_prefix(format!("{} {label}", style(step).bold().dim()));
+        self.bar.set_length(total);
+        self.bar.set_position(progress);
+        let name = file.map(|file| file.name.as_str()).unwrap_or_default();
+        self.bar
+            .set_message(format!("{}/s {name}", HumanBytes(rate)));
+    }
+}
+
+impl ProgressSink for TerminalProgress {
+    fn import_progress(&self, update: ImportProgressUpdate) {
+        self.show(
+            "[1/1]",
+            "Importing",
+            update.progress,
+            update.total,
+            update.rate,
+            update.file.as_ref(),
+        );
+    }
+
+    fn download_progress(&self, update: ProgressUpdate) {
+        let (step, label) = match update.phase {
+            ProgressPhase::Connecting => ("[1/3]", "Connecting"),
+            ProgressPhase::Fetching => ("[2/3]", "Fetching"),
+            ProgressPhase::Exporting => ("[3/3]", "Writing"),
+        };
+        self.show(
+            step,
+            label,
+            update.progress,
+            update.total,
+            update.rate,
+            update.file.as_ref(),
+        );
+    }
+
+    fn reconnecting(&self, event: Reconnecting) {
+        self.bar.println(format!(
+            "connection lost, reconnecting in {} (attempt {}): {}",
+            HumanDuration(Duration::from_millis(event.delay_ms)),
+            event.attempt,
+            event.error
+        ));
+    }
+}
+
+/// Cancel `cancel` on ctrl-c. Abort the returned task once the transfer is done.
+fn cancel_on_interrupt(cancel: &CancelHandle, keep_partial: bool) -> tokio::task::JoinHandle<()> {
+    let cancel = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel.cancel(keep_partial);
+        }
+    })
+}
+
+async fn send(
+    ctx: &TransferContext,
+    progress: &TerminalProgress,
+    paths: Vec<PathBuf>,
+) -> Result<(), SwiftsendError> {
+    let (id, cancel) = transfers::SHARES.insert().await;
+    let interrupt = cancel_on_interrupt(&cancel, false);
+    let result = iroh_send::share(ctx, id, paths, &cancel).await;
+    transfers::SHARES.remove(id).await;
+    interrupt.abort();
+    progress.bar.finish_and_clear();
+    let share = result?;
+    let ticket = share.ticket();
+
+    for (a, b) in share.case_collisions() {
+        eprintln!("warning: {a} and {b} are the same file on case-insensitive filesystems");
+    }
+    eprintln!("sharing, to receive run");
+    eprintln!("    swiftsend-cli receive {ticket}");
+    eprintln!("press ctrl-c to stop");
+    println!("{ticket}");
+    tokio::signal::ctrl_c().await?;
+    share.stop(&ctx.data_dir).await
+}
+
+async fn receive(
+    ctx: &TransferContext,
+    progress: &TerminalProgress,
+    args: ReceiveArgs,
+) -> Result<(), SwiftsendError> {
+    let ticket = iroh_send::parse_ticket(&args.ticket)?;
+    let destination = match args.dir {
+        Some(dir) => std::path::absolute(dir)?,
+        None => std::env::current_dir()?,
+    };
+    let select = (!args.select.is_empty()).then(|| Selection::new(&args.select));
+    let options = ReceiveOptions {
+        limit: args.limit,
+        conflict: args.conflict.unwrap_or_default(),
+        select,
+        ..Default::default()
+    };
+
+    let (id, cancel) = transfers::RECEIVES.insert().await;
+    // keep what has been fetched, running the same receive again resumes it
+    let interrupt = cancel_on_interrupt(&cancel, true);
+    let result = iroh_send::receive(ctx, id, ticket, destination.clone(), options, &cancel).await;
+    transfers::RECEIVES.remove(id).await;
+    interrupt.abort();
+    progress.bar.finish_and_clear();
+    let received = match result {
+        Err(SwiftsendError::Cancelled) => {
+            eprintln!("interrupted, run the same command again to resume");
+            return Err(SwiftsendError::Cancelled);
+        }
+        result => result?,
+    };
+
+    for file in &received.report.files {
+        let action = match file.action {
+            ExportAction::Written => continue,
+            ExportAction::Overwritten => "overwrote",
+            ExportAction::SkippedIdentical => "skipped identical",
+            ExportAction::Renamed => "renamed",
+        };
+        eprintln!("{action} {} -> {}", file.name, file.path.display());
+    }
+    eprintln!(
+        "received {} files, {} into {}",
+        received.report.files.len(),
+        HumanBytes(received.size),
+        destination.display()
+    );
+    Ok(())
+}
+
+async fn inspect(ticket: String, json: bool) -> Result<(), SwiftsendError> {
+    let manifest = iroh_send::inspect(&iroh_send::parse_ticket(&ticket)?, Network::Default).await?;
+    if json {
+        let json = serde_json::to_string_pretty(&manifest).map_err(anyhow::Error::from)?;
+        println!("{json}");
+        return Ok(());
+    }
+    println!("node {}", manifest.node_id);
+    println!("hash {}", manifest.hash);
+    println!(
+        "{} files, {}",
+        manifest.files.len(),
+        HumanBytes(manifest.total_size)
+    );
+    for file in &manifest.files {
+        println!("{:>12} {}", HumanBytes(file.size).to_string(), file.name);
+    }
+    Ok(())
+}
+
+async fn shares(ctx: &TransferContext, tickets: bool) -> Result<(), SwiftsendError> {
+    // a share whose process is gone was never marked as stopped if the
+    // process was killed
+    if let Err(e) = history::close_open_sends(&ctx.data_dir).await {
+        tracing::warn!("failed to close stale shares: {e}");
+    }
+    let now = history::unix_now();
+    let entries = history::entries(&ctx.data_dir).await?;
+    let sends = entries.iter().rev().filter_map(|entry| match entry {
+        HistoryEntry::Send(record) => Some(record),
+        HistoryEntry::Receive(_) => None,
+    });
+    let mut empty = true;
+    for record in sends {
+        empty = false;
+        let status = match record.stopped_at {
+            Some(_) => "stopped",
+            None => "serving",
+        };
+        let age = Duration::from_secs(now.saturating_sub(record.created_at));
+        let paths = match &record.name {
+            Some(name) => format!("{name} (from memory)"),
+            None => record
+                .paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        println!(
+            "{} {status:<7} {:>3} downloads, {} ago: {paths}",
+            &record.hash[..12],
+            record.completed_downloads,
+            HumanDuration(age),
+        );
+        if tickets {
+            println!("    {}", record.ticket);
+        }
+    }
+    if empty {
+        eprintln!("no shares yet");
+    }
+    Ok(())
+}
+
+async fn run_command(cli: Cli) -> Result<(), SwiftsendError> {
+    let progress = Arc::new(TerminalProgress::new());
+    let ctx = TransferContext {
+        data_dir: history::default_data_dir()?,
+        store_dir: iroh_send::default_store_dir(),
+        network: Network::Default,
+        sink: progress.clone(),
+    };
+    match cli.command {
+        Command::Send { paths } => send(&ctx, &progress, paths).await,
+        Command::Receive(args) => receive(&ctx, &progress, args).await,
+        Command::Inspect { ticket, json } => inspect(ticket, json).await,
+        Command::Shares { tickets } => shares(&ctx, tickets).await,
+    }
+}
+
+pub fn run() -> ExitCode {
+    let cli = Cli::parse();
+    if let Err(e) = logging::init_terminal(cli.verbose) {
+        eprintln!("failed to set up logging: {e:#}");
+    }
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match rt.block_on(run_command(cli)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! The commands the frontend invokes.
+//!
+//! These are thin adapters over [`crate::iroh_send`]. They parse the
+//! arguments, run transfers in the background and report back through
+//! events on the [`AppHandle`].
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use iroh_blobs::ticket::BlobTicket;
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::Mutex;
+
+use crate::error::SwiftsendError;
+use crate::export::{ConflictPolicy, ExportReport};
+use crate::history::{self, HistoryEntry, IncompleteDownload};
+use crate::iroh_send::{
+    self, Network, ReceiveOptions, Received, ReceivedText, Share, TransferContext,
+};
+use crate::manifest::{Manifest, Selection};
+use crate::progress::{ImportProgressUpdate, ProgressSink, ProgressUpdate, Reconnecting};
+use crate::retry::RetryPolicy;
+use crate::throttle;
+use crate::transfers::{self, CancelHandle, TransferId};
+
+/// The share the app is serving. Starting another one replaces it.
+static ACTIVE_SHARE: Mutex<Option<Share>> = Mutex::const_new(None);
+
+impl ProgressSink for AppHandle {
+    fn import_progress(&self, update: ImportProgressUpdate) {
+        if let Err(e) = self.emit("upload_progress", update) {
+            tracing::warn!("failed to emit upload progress: {e}");
+        }
+    }
+
+    fn download_progress(&self, update: ProgressUpdate) {
+        if let Err(e) = self.emit("download_progress", update) {
+            tracing::warn!("failed to emit download progress: {e}");
+        }
+    }
+
+    fn reconnecting(&self, event: Reconnecting) {
+        if let Err(e) = self.emit("receive_reconnecting", event) {
+            tracing::warn!("failed to emit receive_reconnecting: {e}");
+        }
+    }
+}
+
+/// The directory the app keeps its history in.
+pub fn data_dir(app: &AppHandle) -> anyhow::Result<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .context("failed to resolve app data dir")
+}
+
+/// Report to the frontend and keep the history in the app data directory.
+fn context(app: &AppHandle) -> Result<TransferContext, SwiftsendError> {
+    Ok(TransferContext {
+        data_dir: data_dir(app)?,
+        store_dir: iroh_send::default_store_dir(),
+        network: Network::Default,
+        sink: Arc::new(app.clone()),
+    })
+}
+
+/// The share that is currently being served, without its ticket.
+#[derive(Debug, Serialize)]
+pub struct ActiveShare {
+    pub hash: String,
+    pub blobs_data_dir: Option<PathBuf>,
+}
+
+pub async fn active_share() -> Option<ActiveShare> {
+    ACTIVE_SHARE.lock().await.as_ref().map(|share| ActiveShare {
+        hash: share.ticket().hash().to_hex(),
+        blobs_data_dir: share.blobs_data_dir().map(Path::to_path_buf),
+    })
+}
+
+#[derive(Clone, Serialize)]
+struct ShareReady {
+    id: TransferId,
+    ticket: Option<String>,
+    error: Option<SwiftsendError>,
+    cancelled: bool,
+    /// Pairs of shared names that only differ in case.
+    case_collisions: Vec<(String, String)>,
+}
+
+/// Start sharing `path` in the background.
+///
+/// Returns the transfer id right away. Importing a large tree can take a
+/// while, so the ticket is delivered through a `share_ready` event with the
+/// same id once the import is done. Until then the share can be stopped with
+/// [`cancel_share`].
+#[tauri::command]
+pub async fn send_files(app: AppHandle, path: String) -> Result<TransferId, SwiftsendError> {
+    start_share(app, move |ctx, id, cancel| async move {
+        iroh_send::share(&ctx, id, vec![PathBuf::from(path)], &cancel).await
+    })
+    .await
+}
+
+/// Share `text` as a single file called `name`, `text.txt` by default.
+///
+/// Works like [`send_files`], but the text is shared straight from memory.
+#[tauri::command]
+pub async fn send_text(
+    app: AppHandle,
+    text: String,
+    name: Option<String>,
+) -> Result<TransferId, SwiftsendError> {
+    let name = name.unwrap_or_else(|| "text.txt".to_string());
+    send_bytes(app, text.into_bytes(), name).await
+}
+
+/// Share `data` as a single file called `name`, straight from memory.
+#[tauri::command]
+pub async fn send_bytes(
+    app: AppHandle,
+    data: Vec<u8>,
+    name: String,
+) -> Result<TransferId, SwiftsendError> {
+    start_share(app, move |ctx, id, cancel| async move {
+        iroh_send::share_bytes(&ctx, id, name, data, &cancel).await
+    })
+    .await
+}
+
+/// Run `share` in the background and make it the active share once it is
+/// ready, see [`send_files`].
+async fn start_share<F, Fut>(app: AppHandle, share: F) -> Result<TransferId, SwiftsendError>
+where
+    F: FnOnce(TransferContext, TransferId, CancelHandle) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Share, SwiftsendError>> + Send,
+{
+    let ctx = context(&app)?;
+    let data_dir = ctx.data_dir.clone();
+    let (id, cancel) = transfers::SHARES.insert().await;
+    tokio::spawn(async move {
+        let result = share(ctx, id, cancel.clone()).await;
+        transfers::SHARES.remove(id).await;
+        let mut case_collisions = Vec::new();
+        let result = match result {
+            Ok(share) => {
+                let ticket = share.ticket().to_string();
+                case_collisions = share.case_collisions().to_vec();
+                let replaced = ACTIVE_SHARE.lock().await.replace(share);
+                // only one share is served at a time, the one it replaces
+                // has to be shut down and closed in the history
+                if let Some(replaced) = replaced {
+                    if let Err(e) = replaced.stop(&data_dir).await {
+                        tracing::warn!("failed to stop replaced share: {e}");
+                    }
+                }
+                Ok(ticket)
+            }
+            Err(e) => Err(e),
+        };
+        let event = ShareReady {
+            id,
+            cancelled: result.is_err() && cancel.is_cancelled(),
+            ticket: result.as_ref().ok().cloned(),
+            error: result.err(),
+            case_collisions,
+        };
+        if let Err(e) = app.emit("share_ready", event) {
+            tracing::warn!("failed to emit share_ready: {e}");
+        }
+    });
+    Ok(id)
+}
+
+/// Stop a share whose import has not finished yet.
+///
+/// The import workers are stopped and the half built store is removed.
+#[tauri::command]
+pub async fn cancel_share(id: TransferId) -> Result<(), SwiftsendError> {
+    if !transfers::SHARES.cancel(id, false).await {
+        return Err(SwiftsendError::UnknownTransfer(id));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn shutdown(app: AppHandle) -> Result<(), SwiftsendError> {
+    let share = ACTIVE_SHARE.lock().await.take();
+    if let Some(share) = share {
+        share.stop(&data_dir(&app)?).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Serialize)]
+struct ReceiveFinished {
+    id: TransferId,
+    error: Option<SwiftsendError>,
+    cancelled: bool,
+    /// What the export did with every file, if the receive completed.
+    report: Option<ExportReport>,
+}
+
+fn receive_options(
+    limit: Option<u64>,
+    retry: Option<RetryPolicy>,
+    conflict: Option<ConflictPolicy>,
+    select: Option<Vec<String>>,
+) -> ReceiveOptions {
+    ReceiveOptions {
+        limit,
+        retry: retry.unwrap_or_default(),
+        conflict: conflict.unwrap_or_default(),
+        select: select.map(|patterns| Selection::new(&patterns)),
+    }
+}
+
+/// Start receiving `ticket` into `path` in the background.
+///
+/// Returns the transfer id right away. A `receive_finished` event with the
+/// same id is emitted once the receive completes, fails or is cancelled.
+///
+/// If the receive fails, the partial store is kept and the download is
+/// listed by `list_incomplete_downloads` so it can be picked up again with
+/// [`resume_receive`].
+///
+/// `select` restricts the receive to the entries matching any of the given
+/// names, directories or glob patterns. Only those are fetched and exported.
+#[tauri::command]
+pub async fn receive_files(
+    app: AppHandle,
+    ticket: String,
+    path: String,
+    limit: Option<u64>,
+    retry: Option<RetryPolicy>,
+    conflict: Option<ConflictPolicy>,
+    select: Option<Vec<String>>,
+) -> Result<TransferId, SwiftsendError> {
+    let ticket = iroh_send::parse_ticket(&ticket)?;
+    tracing::info!("receiving {}", ticket.hash());
+    let options = receive_options(limit, retry, conflict, select);
+    start_receive(app, ticket, PathBuf::from(path), options).await
+}
+
+/// Continue an incomplete receive of `ticket` into `path`.
+///
+/// The partial store from the earlier attempt is reused, so `get_to_db` only
+/// requests the ranges that are not verified locally yet.
+#[tauri::command]
+pub async fn resume_receive(
+    app: AppHandle,
+    ticket: String,
+    path: String,
+    limit: Option<u64>,
+    retry: Option<RetryPolicy>,
+    conflict: Option<ConflictPolicy>,
+    select: Option<Vec<String>>,
+) -> Result<TransferId, SwiftsendError> {
+    let ticket = iroh_send::parse_ticket(&ticket)?;
+    let destination = PathBuf::from(path);
+    if !iroh_send::is_resumable(&data_dir(&app)?, &ticket, &destination).await? {
+        return Err(SwiftsendError::NoIncompleteDownload);
+    }
+    let options = receive_options(limit, retry, conflict, select);
+    start_receive(app, ticket, destination, options).await
+}
+
+/// Delete the partial store of an incomplete receive and forget about it.
+#[tauri::command]
+pub async fn discard_incomplete_download(
+    app: AppHandle,
+    ticket: String,
+    path: String,
+) -> Result<(), SwiftsendError> {
+    let ticket = iroh_send::parse_ticket(&ticket)?;
+    iroh_send::discard_incomplete(&data_dir(&app)?, &ticket, Path::new(&path)).await
+}
+
+async fn start_receive(
+    app: AppHandle,
+    ticket: BlobTicket,
+    destination: PathBuf,
+    options: ReceiveOptions,
+) -> Result<TransferId, SwiftsendError> {
+    let ctx = context(&app)?;
+    let (id, cancel) = transfers::RECEIVES.insert().await;
+    tokio::task::spawn_blocking(move || {
+        let rt = match tokio::runtime::Runtime::new() {
+            Ok(rt) => rt,
+            Err(e) => {
+                finish_receive(&app, id, Err(e.into()), false);
+                return;
+            }
+        };
+        rt.block_on(async move {
+            let result = iroh_send::receive(&ctx, id, ticket, destination, options, &cancel).await;
+            transfers::RECEIVES.remove(id).await;
+            let cancelled = result.is_err() && cancel.is_cancelled();
+            finish_receive(&app, id, result, cancelled);
+        })
+    });
+    Ok(id)
+}
+
+fn finish_receive(
+    app: &AppHandle,
+    id: TransferId,
+    result: Result<Received, SwiftsendError>,
+    cancelled: bool,
+) {
+    let (report, error) = match result {
+        Ok(received) => (Some(received.report), None),
+        Err(e) => (None, Some(e)),
+    };
+    let event = ReceiveFinished {
+        id,
+        error,
+        cancelled,
+        report,
+    };
+    if let Err(e) = app.emit("receive_finished", event) {
+        tracing::warn!("failed to emit receive_finished: {e}");
+    }
+}
+
+/// Stop a running receive.
+///
+/// The partially downloaded store is deleted unless `keep_partial` is set.
+#[tauri::command]
+pub async fn cancel_receive(id: TransferId, keep_partial: bool) -> Result<(), SwiftsendError> {
+    if !transfers::RECEIVES.cancel(id, keep_partial).await {
+        return Err(SwiftsendError::UnknownTransfer(id));
+    }
+    Ok(())
+}
+
+/// Look at the files in `ticket` without downloading them.
+#[tauri::command]
+pub async fn inspect_ticket(ticket: String) -> Result<Manifest, SwiftsendError> {
+    iroh_send::inspect(&iroh_send::parse_ticket(&ticket)?, Network::Default).await
+}
+
+/// Receive the text in `ticket` and return it instead of writing it to disk.
+///
+/// Only works for tickets of a single file of at most
+/// [`iroh_send::MAX_TEXT_SIZE`] bytes of UTF-8 text.
+#[tauri::command]
+pub async fn receive_text(ticket: String) -> Result<ReceivedText, SwiftsendError> {
+    iroh_send::receive_text(&iroh_send::parse_ticket(&ticket)?, Network::Default).await
+}
+
+/// Set the bandwidth limit for receives that were started without one.
+/// `None` or `0` removes the limit. Running receives pick up the change.
+#[tauri::command]
+pub async fn set_download_limit(limit: Option<u64>) {
+    throttle::set_default_limit(limit);
+}
+
+/// Change the bandwidth limit of the running receive `id`.
+/// `None` makes it follow the default again, `0` removes the limit.
+#[tauri::command]
+pub async fn set_receive_limit(id: TransferId, limit: Option<u64>) -> Result<(), SwiftsendError> {
+    if !throttle::set_limit(id, limit).await {
+        return Err(SwiftsendError::UnknownTransfer(id));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn list_history(app: AppHandle) -> Result<Vec<HistoryEntry>, SwiftsendError> {
+    Ok(history::entries(&data_dir(&app)?).await?)
+}
+
+#[tauri::command]
+pub async fn clear_history(app: AppHandle) -> Result<(), SwiftsendError> {
+    Ok(history::clear(&data_dir(&app)?).await?)
+}
+
+#[tauri::command]
+pub async fn list_incomplete_downloads(
+    app: AppHandle,
+) -> Result<Vec<IncompleteDownload>, SwiftsendError> {
+    Ok(history::incomplete_downloads(&data_dir(&app)?).await?)
+}
@@ -0,0 +1,211 @@
+//! A zip file with everything needed to look into a bug report.
+//!
+//! Tickets are all a peer needs to fetch a share, so every ticket in the
+//! bundle, including any that made it into the logs, is redacted.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use tauri::AppHandle;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    commands::{self, ActiveShare},
+    error::SwiftsendError,
+    history, logging, throttle,
+    transfers::{self, TransferId},
+};
+
+/// Tickets are `blob` followed by a base32 payload far longer than this.
+const MIN_TICKET_PAYLOAD: usize = 32;
+
+#[derive(Debug, Serialize)]
+struct Settings {
+    version: String,
+    os: &'static str,
+    arch: &'static str,
+    default_download_limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareState {
+    active_share: Option<ActiveShare>,
+    shares: Vec<TransferId>,
+    receives: Vec<TransferId>,
+}
+
+/// Replace everything that looks like a ticket in `text`.
+fn redact_tickets(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("blob") {
+        let (before, candidate) = rest.split_at(start);
+        redacted.push_str(before);
+        let payload = candidate[4..]
+            .bytes()
+            .take_while(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(b))
+            .count();
+        if payload >= MIN_TICKET_PAYLOAD {
+            redacted.push_str("blob<redacted>");
+        } else {
+            redacted.push_str(&candidate[..4 + payload]);
+        }
+        rest = &candidate[4 + payload..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Log files and the history, by their name in the bundle.
+fn collect_files(app: &AppHandle) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    let log_dir = logging::log_dir(app)?;
+    if log_dir.exists() {
+        for entry in std::fs::read_dir(&log_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(logging::LOG_FILE_PREFIX) && entry.file_type()?.is_file() {
+                files.push((format!("logs/{name}"), entry.path()));
+            }
+        }
+    }
+    for path in history::files(&commands::data_dir(app)?) {
+        if let Some(name) = path.file_name() {
+            let name = name.to_string_lossy().into_owned();
+            files.push((name, path));
+        }
+    }
+    Ok(files)
+}
+
+fn write_bundle(
+    target: &Path,
+    files: &[(String, PathBuf)],
+    generated: &[(&str, String)],
+) -> anyhow::Result<()> {
+    let mut zip = ZipWriter::new(std::fs::File::create(target)?);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, path) in files {
+        let text = match std::fs::read(path) {
+            Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(redact_tickets(&text).as_bytes())?;
+    }
+    for (name, text) in generated {
+        zip.start_file(*name, options)?;
+        zip.write_all(redact_tickets(text).as_bytes())?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Write a diagnostics bundle to `path`, or to the download directory if no
+/// path is given, and return where it was written.
+///
+/// The bundle holds the log files, the app settings, the state of running
+/// shares and receives and the transfer history.
+#[tauri::command]
+pub async fn export_diagnostics(
+    app: AppHandle,
+    path: Option<String>,
+) -> Result<String, SwiftsendError> {
+    let target = match path {
+        Some(path) => PathBuf::from(path),
+        None => dirs::download_dir()
+            .ok_or_else(|| SwiftsendError::Other("No download directory".to_string()))?
+            .join(format!("swiftsend-diagnostics-{}.zip", history::unix_now())),
+    };
+
+    let settings = Settings {
+        version: app.package_info().version.to_string(),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        default_download_limit: throttle::default_limit(),
+    };
+    let state = ShareState {
+        active_share: commands::active_share().await,
+        shares: transfers::SHARES.ids().await,
+        receives: transfers::RECEIVES.ids().await,
+    };
+    let generated = [
+        (
+            "settings.json",
+            serde_json::to_string_pretty(&settings).map_err(anyhow::Error::from)?,
+        ),
+        (
+            "state.json",
+            serde_json::to_string_pretty(&state).map_err(anyhow::Error::from)?,
+        ),
+    ];
+    let files = collect_files(&app)?;
+
+    let bundle = target.clone();
+    tokio::task::spawn_blocking(move || write_bundle(&bundle, &files, &generated))
+        .await
+        .map_err(|e| SwiftsendError::Other(e.to_string()))??;
+    tracing::info!("wrote diagnostics bundle to {}", target.display());
+    Ok(target.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::{NodeAddr, SecretKey};
+    use iroh_blobs::{ticket::BlobTicket, BlobFormat, Hash};
+
+    use super::*;
+
+    fn ticket() -> String {
+        let node = SecretKey::from_bytes(&[7; 32]).public();
+        BlobTicket::new(NodeAddr::new(node), Hash::new(b"data"), BlobFormat::HashSeq)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn redacts_tickets_anywhere() {
+        let ticket = ticket();
+        assert_eq!(redact_tickets(&ticket), "blob<redacted>");
+        assert_eq!(
+            redact_tickets(&format!("{ticket} was shared")),
+            "blob<redacted> was shared"
+        );
+        assert_eq!(
+            redact_tickets(&format!("receiving {ticket}")),
+            "receiving blob<redacted>"
+        );
+        assert_eq!(
+            redact_tickets(&format!("{ticket}\n{ticket}")),
+            "blob<redacted>\nblob<redacted>"
+        );
+    }
+
+    #[test]
+    fn redacts_tickets_in_json_strings() {
+        let ticket = ticket();
+        let json = format!("[{{\"ticket\":\"{ticket}\",\"hash\":\"abc\"}}]");
+        assert_eq!(
+            redact_tickets(&json),
+            "[{\"ticket\":\"blob<redacted>\",\"hash\":\"abc\"}]"
+        );
+    }
+
+    #[test]
+    fn keeps_words_that_only_start_like_a_ticket() {
+        for text in [
+            "blob",
+            "blobs",
+            "failed to export blob 3 of 5",
+            "blob_store: 12 blobs",
+            "Blobs are fine",
+            "{\"kind\":\"blobs\"}",
+        ] {
+            assert_eq!(redact_tickets(text), text);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Free space checks before a receive writes anything.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use walkdir::WalkDir;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpaceError {
+    #[error(
+        "insufficient space on {}: {needed} bytes needed, {available} bytes available",
+        path.display()
+    )]
+    Insufficient {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    #[error("failed to query free space: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// The closest ancestor of `path` that exists, so we can ask about a
+/// directory before it has been created.
+fn existing_ancestor(path: &Path) -> io::Result<&Path> {
+    path.ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no existing ancestor"))
+}
+
+#[cfg(unix)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(existing_ancestor(path)?.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(windows)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path = existing_ancestor(path)?
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+    let mut available = 0u64;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let a = std::fs::metadata(existing_ancestor(a)?)?;
+    let b = std::fs::metadata(existing_ancestor(b)?)?;
+    Ok(a.dev() == b.dev())
+}
+
+#[cfg(windows)]
+fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    let volume = |p: &Path| -> io::Result<Option<std::ffi::OsString>> {
+        let p = existing_ancestor(p)?.canonicalize()?;
+        Ok(p.components()
+            .next()
+            .map(|c| c.as_os_str().to_ascii_uppercase()))
+    };
+    Ok(volume(a)? == volume(b)?)
+}
+
+/// Bytes already on disk below `dir`, e.g. in a partial store being resumed.
+fn dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+fn ensure_space(path: &Path, needed: u64) -> Result<(), SpaceError> {
+    let available = available_space(path)?;
+    if available < needed {
+        return Err(SpaceError::Insufficient {
+            path: path.to_path_buf(),
+            needed,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Check that downloading `size` bytes into `store_dir` and exporting them
+/// to `destination` fits.
+///
+/// The export copies the data out of the store, so the destination needs
+/// room for a full second copy, on top of the store if both are on the same
+/// filesystem.
+pub fn check_receive_space(
+    store_dir: &Path,
+    destination: &Path,
+    size: u64,
+) -> Result<(), SpaceError> {
+    let remaining = size.saturating_sub(dir_size(store_dir));
+    if same_filesystem(store_dir, destination)? {
+        ensure_space(destination, remaining.saturating_add(size))
+    } else {
+        ensure_space(store_dir, remaining)?;
+        ensure_space(destination, size)
+    }
+}
+
+/// Check that exporting `size` bytes from the store to `destination` fits.
+pub fn check_export_space(destination: &Path, size: u64) -> Result<(), SpaceError> {
+    ensure_space(destination, size)
+}
@@ -0,0 +1,170 @@
+//! The error type the commands return to the frontend.
+
+use std::{io, path::PathBuf};
+
+use iroh_blobs::get::error::GetError;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use serde_json::json;
+
+use crate::{disk::SpaceError, transfers::TransferId};
+
+/// Everything a command or a background transfer can fail with.
+///
+/// Serialized as `{ kind, message, details }`. `kind` is a stable snake case
+/// code to match on, `message` is meant for humans and may change, and
+/// `details` holds the structured fields of the kinds that have any.
+#[derive(Debug, thiserror::Error)]
+pub enum SwiftsendError {
+    #[error("invalid ticket: {0}")]
+    InvalidTicket(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("failed to reach the sender: {0}")]
+    Connection(String),
+    #[error("the sender does not have the requested data: {0}")]
+    NotFound(String),
+    #[error("data from the sender failed verification: {0}")]
+    Verification(String),
+    #[error("blob store error: {0}")]
+    Store(String),
+    #[error("{} already exists", path.display())]
+    DestinationExists { path: PathBuf },
+    #[error("invalid file name {name:?}: {reason}")]
+    InvalidName { name: String, reason: String },
+    #[error(
+        "insufficient space on {}: {needed} bytes needed, {available} bytes available",
+        path.display()
+    )]
+    InsufficientSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("i/o error: {0}")]
+    Io(String),
+    #[error("no files in the collection match the selection")]
+    EmptySelection,
+    #[error("no incomplete download for this ticket and destination")]
+    NoIncompleteDownload,
+    #[error("no transfer in progress with id {0}")]
+    UnknownTransfer(TransferId),
+    #[error("cancelled")]
+    Cancelled,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl SwiftsendError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::InvalidTicket(_) => "invalid_ticket",
+            Self::InvalidArgument(_) => "invalid_argument",
+            Self::Connection(_) => "connection",
+            Self::NotFound(_) => "not_found",
+            Self::Verification(_) => "verification",
+            Self::Store(_) => "store",
+            Self::DestinationExists { .. } => "destination_exists",
+            Self::InvalidName { .. } => "invalid_name",
+            Self::InsufficientSpace { .. } => "insufficient_space",
+            Self::PermissionDenied(_) => "permission_denied",
+            Self::Io(_) => "io",
+            Self::EmptySelection => "empty_selection",
+            Self::NoIncompleteDownload => "no_incomplete_download",
+            Self::UnknownTransfer(_) => "unknown_transfer",
+            Self::Cancelled => "cancelled",
+            Self::Other(_) => "other",
+        }
+    }
+
+    fn details(&self) -> serde_json::Value {
+        match self {
+            Self::DestinationExists { path } => json!({ "path": path.to_string_lossy() }),
+            Self::InvalidName { name, reason } => json!({ "name": name, "reason": reason }),
+            Self::InsufficientSpace {
+                path,
+                needed,
+                available,
+            } => json!({
+                "path": path.to_string_lossy(),
+                "needed": needed,
+                "available": available,
+            }),
+            Self::UnknownTransfer(id) => json!({ "id": id }),
+            _ => serde_json::Value::Null,
+        }
+    }
+}
+
+impl Serialize for SwiftsendError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("SwiftsendError", 3)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("details", &self.details())?;
+        state.end()
+    }
+}
+
+impl From<io::Error> for SwiftsendError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(e.to_string()),
+            _ => Self::Io(e.to_string()),
+        }
+    }
+}
+
+impl From<SpaceError> for SwiftsendError {
+    fn from(e: SpaceError) -> Self {
+        match e {
+            SpaceError::Insufficient {
+                path,
+                needed,
+                available,
+            } => Self::InsufficientSpace {
+                path,
+                needed,
+                available,
+            },
+            SpaceError::Io(e) => e.into(),
+        }
+    }
+}
+
+impl From<GetError> for SwiftsendError {
+    fn from(e: GetError) -> Self {
+        match e {
+            GetError::NotFound(e) => Self::NotFound(e.to_string()),
+            GetError::RemoteReset(e) | GetError::Io(e) => Self::Connection(e.to_string()),
+            // data that does not match its hash ends up here
+            GetError::NoncompliantNode(e) => Self::Verification(e.to_string()),
+            GetError::LocalFailure(e) => Self::Store(e.to_string()),
+            GetError::BadRequest(e) => Self::Other(e.to_string()),
+        }
+    }
+}
+
+/// Errors from code that uses `anyhow` internally keep their kind if they
+/// started out as one of the types above.
+impl From<anyhow::Error> for SwiftsendError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<SwiftsendError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<SpaceError>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<GetError>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => Self::Other(format!("{e:#}")),
+        }
+    }
+}
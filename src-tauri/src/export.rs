@@ -0,0 +1,931 @@
+//! Writing a received collection out to the destination directory.
+//!
+//! Exporting happens in two steps. First every entry is mapped to a target
+//! path and the [`ConflictPolicy`] decides what happens with targets that
+//! already exist. Only once the whole plan is known is anything written, so
+//! a conflict under [`ConflictPolicy::Fail`] never leaves a half exported tree.
+//!
+//! The files are then written into a hidden staging directory on the same
+//! filesystem as the destination by [`stage`], and moved into place with
+//! renames by [`StagedExport::commit`] once all of them have been written.
+//! Anyone watching the destination only ever sees complete files and, for a
+//! shared directory, a complete tree.
+
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use iroh_blobs::{
+    format::collection::Collection,
+    store::{ExportMode, ExportProgressCb, MapEntry},
+    Hash,
+};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::SwiftsendError;
+use crate::sanitize::{
+    collision_key, decode_component, has_escaped_bytes, sanitize_component, Platform,
+    SUPPORTS_RAW_NAMES,
+};
+
+/// What to do when an entry of the collection already exists in the destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Abort the export before writing anything.
+    #[default]
+    Fail,
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file if it has the same content, fail otherwise.
+    SkipIdentical,
+    /// Write to the first free `name (n).ext` next to the existing file.
+    Rename,
+}
+
+/// What the export did with a single entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportAction {
+    Written,
+    Overwritten,
+    SkippedIdentical,
+    Renamed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedFile {
+    /// The name of the entry in the collection.
+    pub name: String,
+    /// Raw names that are not valid UTF-8 are shown lossily.
+    #[serde(serialize_with = "serialize_lossy")]
+    pub path: PathBuf,
+    pub action: ExportAction,
+    /// The name had to be changed for the local filesystem to store it.
+    pub sanitized: bool,
+}
+
+fn serialize_lossy<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExportReport {
+    pub files: Vec<ExportedFile>,
+}
+
+/// Progress of writing the files into the destination.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportProgress {
+    /// The name of the entry being written.
+    pub name: String,
+    pub file_offset: u64,
+    pub file_size: u64,
+    /// Bytes written so far over all files.
+    pub offset: u64,
+    /// Bytes to write over all files. Skipped files don't count.
+    pub total: u64,
+}
+
+pub type OnExportProgress = Arc<dyn Fn(ExportProgress) + Send + Sync + 'static>;
+
+#[derive(Debug)]
+struct PlannedFile {
+    name: String,
+    hash: Hash,
+    size: u64,
+    target: PathBuf,
+    action: ExportAction,
+    sanitized: bool,
+}
+
+/// Whether `component` would be read as a drive on Windows, `C:` on its own
+/// or followed by a separator. Other names with a colon, like `x:notes.txt`,
+/// are taken care of by the sanitizer.
+fn is_drive_prefix(component: &str) -> bool {
+    match component.as_bytes() {
+        [drive, b':'] | [drive, b':', b'\\' | b'/', ..] => drive.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+/// Names in a collection come from the sender and can't be trusted. Every
+/// component has to be a plain name that stays inside its parent directory.
+fn validate_path_component(component: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(!component.is_empty(), "path components must not be empty");
+    anyhow::ensure!(
+        component != "." && component != "..",
+        "path components must not be {component:?}"
+    );
+    anyhow::ensure!(
+        !component.contains('/'),
+        "path components must not contain the only correct path separator, /"
+    );
+    anyhow::ensure!(
+        !component.contains('\\'),
+        "path components must not contain a backslash: {component:?}"
+    );
+    anyhow::ensure!(
+        !component.chars().any(char::is_control),
+        "path components must not contain control characters: {component:?}"
+    );
+    anyhow::ensure!(
+        !(cfg!(windows) && is_drive_prefix(component)),
+        "path components must not be a drive prefix: {component:?}"
+    );
+    Ok(())
+}
+
+/// Make sure `path` does not leave `root` through a symlink that already
+/// exists somewhere below `root`.
+fn ensure_within_root(root: &Path, path: &Path) -> anyhow::Result<()> {
+    let Ok(root) = root.canonicalize() else {
+        // nothing below a missing root exists, so there is no symlink to follow
+        return Ok(());
+    };
+    let Some(existing) = path.ancestors().find(|p| p.symlink_metadata().is_ok()) else {
+        return Ok(());
+    };
+    let resolved = existing
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("failed to resolve {}: {e}", existing.display()))?;
+    anyhow::ensure!(
+        resolved.starts_with(&root),
+        "{} resolves to {}, outside of {}",
+        path.display(),
+        resolved.display(),
+        root.display()
+    );
+    Ok(())
+}
+
+/// Whether [`get_export_path`] changes any component of `name`, other than
+/// turning escaped bytes back into the raw name.
+fn is_sanitized(name: &str) -> bool {
+    name.split('/').any(|part| {
+        sanitize_component(part, Platform::CURRENT) != part
+            || (!SUPPORTS_RAW_NAMES && has_escaped_bytes(part))
+    })
+}
+
+/// Where the entry `name` of a collection goes below `root`.
+///
+/// Fails with [`SwiftsendError::InvalidName`] for names that would escape `root`.
+pub fn get_export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    export_path(root, name).map_err(|e| {
+        SwiftsendError::InvalidName {
+            name: name.to_string(),
+            reason: e.to_string(),
+        }
+        .into()
+    })
+}
+
+fn export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(!name.starts_with('/'), "name {name:?} must not be absolute");
+    let parts = name.split('/');
+    let mut path = root.to_path_buf();
+    for part in parts {
+        validate_path_component(part)?;
+        path.push(decode_component(&sanitize_component(
+            part,
+            Platform::CURRENT,
+        )));
+    }
+    ensure_within_root(root, &path)?;
+    Ok(path)
+}
+
+/// The `n`th alternative for `path`, e.g. `report (2).pdf`.
+fn renamed_path(path: &Path, n: usize) -> PathBuf {
+    // built from the raw name, which does not have to be valid UTF-8
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(" ({n})"));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+/// Hash the file at `path` the same way the store does.
+async fn hash_file(path: PathBuf) -> anyhow::Result<Hash> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut buf = vec![0u8; 1024 * 64];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        anyhow::Ok(Hash::from(hasher.finalize()))
+    })
+    .await?
+}
+
+/// Whether names that only differ in case refer to the same file in `dir`.
+async fn is_case_insensitive(dir: &Path) -> anyhow::Result<bool> {
+    let probe = dir.join(".sendme-case-probe");
+    tokio::fs::write(&probe, b"").await?;
+    let insensitive = tokio::fs::symlink_metadata(dir.join(".SENDME-CASE-PROBE"))
+        .await
+        .is_ok();
+    tokio::fs::remove_file(&probe).await?;
+    Ok(insensitive)
+}
+
+/// Decide where every entry goes and what happens to existing files.
+///
+/// Entries that would land on the same file, because sanitizing made their
+/// names equal or because the filesystem ignores case, are written next to
+/// each other as `name (n).ext`, whatever the policy.
+async fn plan(
+    db: &impl iroh_blobs::store::Store,
+    collection: &Collection,
+    root: &Path,
+    policy: ConflictPolicy,
+    case_insensitive: bool,
+) -> anyhow::Result<Vec<PlannedFile>> {
+    let key = |path: &Path| {
+        if case_insensitive {
+            OsString::from(collision_key(&path.to_string_lossy()))
+        } else {
+            path.as_os_str().to_os_string()
+        }
+    };
+    // all targets of the collection, so a rename never picks the name of
+    // another entry that has not been written yet
+    let mut claimed = collection
+        .iter()
+        .map(|(name, _)| get_export_path(root, name).map(|path| key(&path)))
+        .collect::<anyhow::Result<BTreeSet<_>>>()?;
+    let free_name = |target: &Path, claimed: &BTreeSet<OsString>| {
+        (1..)
+            .map(|n| renamed_path(target, n))
+            .find(|p| !claimed.contains(&key(p)) && p.symlink_metadata().is_err())
+            .expect("there is always a free name")
+    };
+    // targets of the entries planned so far
+    let mut taken = BTreeSet::new();
+    let mut planned = Vec::with_capacity(collection.len());
+    for (name, hash) in collection.iter() {
+        let target = get_export_path(root, name)?;
+        let exists = tokio::fs::symlink_metadata(&target).await.is_ok();
+        let (target, action) = match policy {
+            _ if taken.contains(&key(&target)) => {
+                tracing::warn!("{name} collides with another entry, renaming it");
+                let renamed = free_name(&target, &claimed);
+                claimed.insert(key(&renamed));
+                (renamed, ExportAction::Renamed)
+            }
+            _ if !exists => (target, ExportAction::Written),
+            ConflictPolicy::Fail => {
+                return Err(SwiftsendError::DestinationExists { path: target }.into());
+            }
+            ConflictPolicy::Overwrite => {
+                // a directory is never replaced
+                if target.is_dir() {
+                    return Err(SwiftsendError::DestinationExists { path: target }.into());
+                }
+                (target, ExportAction::Overwritten)
+            }
+            ConflictPolicy::SkipIdentical => {
+                if !target.is_file() || hash_file(target.clone()).await? != *hash {
+                    return Err(SwiftsendError::DestinationExists { path: target }.into());
+                }
+                (target, ExportAction::SkippedIdentical)
+            }
+            ConflictPolicy::Rename => {
+                let renamed = free_name(&target, &claimed);
+                claimed.insert(key(&renamed));
+                (renamed, ExportAction::Renamed)
+            }
+        };
+        taken.insert(key(&target));
+        let entry = db
+            .get(hash)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("blob for {name} is missing from the store"))?;
+        planned.push(PlannedFile {
+            name: name.clone(),
+            hash: *hash,
+            size: entry.size().value(),
+            target,
+            action,
+            sanitized: is_sanitized(name),
+        });
+    }
+    Ok(planned)
+}
+
+/// Write `collection` into the `staging` directory for an export into `path`.
+///
+/// `staging` must be on the same filesystem as `path`. It is removed again
+/// if staging fails, otherwise by [`StagedExport::commit`].
+///
+/// The data is copied out of the store rather than moved, so a failed or
+/// interrupted export leaves the store intact and the receive can be resumed.
+pub async fn stage(
+    db: impl iroh_blobs::store::Store,
+    collection: Collection,
+    path: &Path,
+    staging: &Path,
+    policy: ConflictPolicy,
+    on_progress: OnExportProgress,
+) -> anyhow::Result<StagedExport> {
+    tracing::info!("exporting {} files to {}", collection.len(), path.display());
+
+    // a staging directory left behind by a crash only holds partial data
+    if staging.exists() {
+        tokio::fs::remove_dir_all(staging).await?;
+    }
+    tokio::fs::create_dir_all(staging).await?;
+
+    let result = async {
+        let case_insensitive = is_case_insensitive(staging).await?;
+        let planned = plan(&db, &collection, path, policy, case_insensitive).await?;
+        stage_files(&db, &planned, path, staging, on_progress).await?;
+        anyhow::Ok(planned)
+    }
+    .await;
+    match result {
+        Ok(planned) => Ok(StagedExport {
+            root: path.to_path_buf(),
+            staging: staging.to_path_buf(),
+            planned,
+        }),
+        Err(e) => {
+            remove_staging(staging).await;
+            Err(e)
+        }
+    }
+}
+
+async fn remove_staging(staging: &Path) {
+    if let Err(e) = tokio::fs::remove_dir_all(staging).await {
+        tracing::warn!("failed to remove staging directory: {e}");
+    }
+}
+
+/// An export whose files have all been written to the staging directory.
+///
+/// Dropping it leaves the staging directory behind, the next export into
+/// the same destination starts by removing it.
+#[derive(Debug)]
+#[must_use = "nothing is in the destination before the export is committed"]
+pub struct StagedExport {
+    root: PathBuf,
+    staging: PathBuf,
+    planned: Vec<PlannedFile>,
+}
+
+impl StagedExport {
+    /// Move the staged files into the destination.
+    ///
+    /// The renames run on a blocking thread and go on when this future is
+    /// dropped, so a caller that may be cancelled has to decide before it
+    /// starts the commit rather than race it.
+    pub async fn commit(self) -> anyhow::Result<ExportReport> {
+        let Self {
+            root,
+            staging,
+            planned,
+        } = self;
+        let committed = {
+            let staging = staging.clone();
+            tokio::task::spawn_blocking(move || commit(&staging, &root)).await
+        };
+        remove_staging(&staging).await;
+        committed??;
+
+        let files = planned
+            .into_iter()
+            .map(|file| ExportedFile {
+                name: file.name,
+                path: file.target,
+                action: file.action,
+                sanitized: file.sanitized,
+            })
+            .collect();
+        Ok(ExportReport { files })
+    }
+}
+
+async fn stage_files(
+    db: &impl iroh_blobs::store::Store,
+    planned: &[PlannedFile],
+    root: &Path,
+    staging: &Path,
+    on_progress: OnExportProgress,
+) -> anyhow::Result<()> {
+    let to_write = planned
+        .iter()
+        .filter(|file| file.action != ExportAction::SkippedIdentical);
+    let total = to_write.clone().map(|file| file.size).sum::<u64>();
+    let mut done = 0;
+    for file in to_write {
+        let staged = staging.join(file.target.strip_prefix(root)?);
+        let progress = ExportProgress {
+            name: file.name.clone(),
+            file_offset: 0,
+            file_size: file.size,
+            offset: done,
+            total,
+        };
+        on_progress(progress.clone());
+        let cb: ExportProgressCb = {
+            let on_progress = on_progress.clone();
+            let progress = progress.clone();
+            Box::new(move |offset| {
+                on_progress(ExportProgress {
+                    file_offset: offset,
+                    offset: progress.offset + offset,
+                    ..progress.clone()
+                });
+                Ok(())
+            })
+        };
+        // exports done by reference or reflink never call the callback,
+        // so report the file as complete once the export returns
+        export_blob(db, file.hash, staged, cb).await?;
+        done += file.size;
+        on_progress(ExportProgress {
+            file_offset: file.size,
+            offset: done,
+            ..progress
+        });
+    }
+    Ok(())
+}
+
+/// What a commit changed in the destination, to undo it when a move fails.
+#[derive(Debug, Default)]
+struct Moved {
+    /// Entries that did not exist before.
+    created: Vec<PathBuf>,
+    /// Overwritten files and where the originals were moved aside to.
+    replaced: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Move everything in `staging` into `root`.
+///
+/// Entries that do not exist in `root` yet are moved with a single rename,
+/// directories that do exist are merged into. Files that are overwritten are
+/// moved aside first, next to `staging`. If a move fails, the entries
+/// created so far are removed again and the overwritten files restored.
+fn commit(staging: &Path, root: &Path) -> std::io::Result<()> {
+    let mut name = staging.file_name().unwrap_or_default().to_os_string();
+    name.push("-replaced");
+    let aside = staging.with_file_name(name);
+    let mut moved = Moved::default();
+    let result = move_into(staging, root, &aside, &mut moved);
+    if result.is_err() {
+        for path in moved.created.iter().rev() {
+            let removed = if path.is_dir() {
+                std::fs::remove_dir_all(path)
+            } else {
+                std::fs::remove_file(path)
+            };
+            if let Err(e) = removed {
+                tracing::warn!("failed to roll back {}: {e}", path.display());
+            }
+        }
+        for (target, original) in moved.replaced.iter().rev() {
+            if let Err(e) = std::fs::rename(original, target) {
+                tracing::warn!(
+                    "failed to restore {}, the original is at {}: {e}",
+                    target.display(),
+                    original.display()
+                );
+            }
+        }
+    }
+    if aside.exists() {
+        // only empty once every original is back in place or replaced for good
+        let removed = match result {
+            Ok(()) => std::fs::remove_dir_all(&aside),
+            Err(_) => std::fs::remove_dir(&aside),
+        };
+        if let Err(e) = removed {
+            tracing::warn!("failed to remove {}: {e}", aside.display());
+        }
+    }
+    result
+}
+
+fn move_into(from: &Path, to: &Path, aside: &Path, moved: &mut Moved) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        match target.symlink_metadata() {
+            Ok(meta) if meta.is_dir() && entry.file_type()?.is_dir() => {
+                move_into(&entry.path(), &target, aside, moved)?;
+            }
+            // an overwritten file, the plan made sure this is not a directory
+            Ok(_) => {
+                std::fs::create_dir_all(aside)?;
+                let original = aside.join(moved.replaced.len().to_string());
+                std::fs::rename(&target, &original)?;
+                moved.replaced.push((target.clone(), original));
+                std::fs::rename(entry.path(), &target)?;
+            }
+            Err(_) => {
+                std::fs::rename(entry.path(), &target)?;
+                moved.created.push(target);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn export_blob(
+    db: &impl iroh_blobs::store::Store,
+    hash: Hash,
+    target: PathBuf,
+    progress: ExportProgressCb,
+) -> anyhow::Result<()> {
+    // a reference would make the store point at the staged file, which is
+    // gone once the staging directory is cleaned up after a failure
+    db.export(hash, target, ExportMode::Copy, progress).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_blobs::{
+        store::{fs, Store as _},
+        BlobFormat, TempTag,
+    };
+
+    use super::*;
+
+    /// A store in `dir` holding `entries`, with the tags that keep them alive.
+    async fn store_with(
+        dir: &Path,
+        entries: &[(&str, &[u8])],
+    ) -> (fs::Store, Collection, Vec<TempTag>) {
+        let db = fs::Store::load(dir.join("store")).await.unwrap();
+        let mut collection = Collection::default();
+        let mut tags = Vec::new();
+        for (name, data) in entries {
+            let tag = db
+                .import_bytes(data.to_vec().into(), BlobFormat::Raw)
+                .await
+                .unwrap();
+            collection.push(name.to_string(), *tag.hash());
+            tags.push(tag);
+        }
+        (db, collection, tags)
+    }
+
+    fn no_progress() -> OnExportProgress {
+        Arc::new(|_| {})
+    }
+
+    fn assert_rejected(name: &str) {
+        let root = tempfile::tempdir().unwrap();
+        assert!(
+            get_export_path(root.path(), name).is_err(),
+            "{name:?} should be rejected"
+        );
+    }
+
+    #[test]
+    fn accepts_nested_names() {
+        let root = tempfile::tempdir().unwrap();
+        let path = get_export_path(root.path(), "dir/sub dir/file.tar.gz").unwrap();
+        assert_eq!(
+            path,
+            root.path().join("dir").join("sub dir").join("file.tar.gz")
+        );
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        assert_rejected("..");
+        assert_rejected("../../.bashrc");
+        assert_rejected("dir/../../escape");
+        assert_rejected("dir/..");
+    }
+
+    #[test]
+    fn rejects_current_and_empty_components() {
+        assert_rejected("");
+        assert_rejected(".");
+        assert_rejected("./file");
+        assert_rejected("dir//file");
+        assert_rejected("dir/");
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert_rejected("/etc/passwd");
+        assert_rejected("//server/share/file");
+    }
+
+    #[test]
+    fn rejects_backslashes() {
+        assert_rejected("..\\..\\escape");
+        assert_rejected("dir\\file");
+        assert_rejected("\\\\server\\share");
+    }
+
+    #[test]
+    fn recognizes_drive_prefixes() {
+        assert!(is_drive_prefix("C:"));
+        assert!(is_drive_prefix("c:\\Windows"));
+        assert!(!is_drive_prefix("D:file"));
+        assert!(!is_drive_prefix("a:b"));
+        assert!(!is_drive_prefix("1:"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn rejects_drive_prefixes() {
+        assert_rejected("C:");
+        assert_rejected("c:/Windows/System32/evil.dll");
+    }
+
+    #[test]
+    fn accepts_names_with_colons() {
+        let root = tempfile::tempdir().unwrap();
+        for name in ["a:b", "x:notes.txt", "dir/D:file"] {
+            let path = get_export_path(root.path(), name).unwrap();
+            assert!(path.starts_with(root.path()), "{name:?}");
+        }
+        #[cfg(not(windows))]
+        assert_eq!(
+            get_export_path(root.path(), "C:/file").unwrap(),
+            root.path().join("C:").join("file")
+        );
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        assert_rejected("file\0.txt");
+        assert_rejected("dir/new\nline");
+        assert_rejected("bell\u{7}");
+        assert_rejected("del\u{7f}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_escape_through_symlink() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        assert!(get_export_path(root.path(), "link/file").is_err());
+        assert!(get_export_path(root.path(), "link").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn accepts_symlink_inside_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("real")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("real"), root.path().join("link")).unwrap();
+        assert!(get_export_path(root.path(), "link/file").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_commit_rolls_back_and_keeps_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        // larger than what the store inlines, so every blob has its own file
+        let a = vec![1u8; 100 * 1024];
+        let b = vec![2u8; 100 * 1024];
+        let (db, collection, _tags) =
+            store_with(dir.path(), &[("a.bin", &a), ("dir/b.bin", &b)]).await;
+        let root = dir.path().join("root");
+        let staging = dir.path().join("staging");
+        std::fs::create_dir(&root).unwrap();
+        // a file where the export needs a directory makes the final move fail
+        std::fs::write(root.join("dir"), b"in the way").unwrap();
+
+        let result = export(
+            db.clone(),
+            collection.clone(),
+            &root,
+            &staging,
+            ConflictPolicy::Fail,
+            no_progress(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(!root.join("a.bin").exists());
+        assert_eq!(std::fs::read(root.join("dir")).unwrap(), b"in the way");
+        assert!(!staging.exists());
+
+        // the store still has all of the data, so the export can be retried
+        std::fs::remove_file(root.join("dir")).unwrap();
+        export(
+            db,
+            collection,
+            &root,
+            &staging,
+            ConflictPolicy::Fail,
+            no_progress(),
+        )
+        .await
+        .unwrap();
+        assert!(std::fs::read(root.join("a.bin")).unwrap() == a);
+        assert!(std::fs::read(root.join("dir/b.bin")).unwrap() == b);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_commit_restores_overwritten_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let staging = dir.path().join("staging");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(staging.join("sub")).unwrap();
+        // enough files that some are replaced before the move that fails,
+        // whatever order the directory is read in
+        for i in 0..16 {
+            for (tree, content) in [(&root, "old"), (&staging, "new")] {
+                std::fs::write(tree.join(format!("{i}.txt")), content).unwrap();
+                std::fs::write(tree.join("sub").join(format!("{i}.txt")), content).unwrap();
+            }
+        }
+        std::fs::create_dir_all(staging.join("blocked")).unwrap();
+        std::fs::write(staging.join("blocked/file"), "new").unwrap();
+        std::fs::write(root.join("blocked"), "in the way").unwrap();
+
+        assert!(commit(&staging, &root).is_err());
+        for i in 0..16 {
+            assert_eq!(
+                std::fs::read(root.join(format!("{i}.txt"))).unwrap(),
+                b"old"
+            );
+            assert_eq!(
+                std::fs::read(root.join("sub").join(format!("{i}.txt"))).unwrap(),
+                b"old"
+            );
+        }
+        assert_eq!(std::fs::read(root.join("blocked")).unwrap(), b"in the way");
+        assert!(!dir.path().join("staging-replaced").exists());
+    }
+
+    /// [`stage`] and commit in one go.
+    async fn export(
+        db: fs::Store,
+        collection: Collection,
+        path: &Path,
+        staging: &Path,
+        policy: ConflictPolicy,
+        on_progress: OnExportProgress,
+    ) -> anyhow::Result<ExportReport> {
+        stage(db, collection, path, staging, policy, on_progress)
+            .await?
+            .commit()
+            .await
+    }
+
+    /// Export `collection` into `dir/root` with `policy`.
+    async fn export_with(
+        db: &fs::Store,
+        collection: &Collection,
+        dir: &Path,
+        policy: ConflictPolicy,
+    ) -> anyhow::Result<ExportReport> {
+        let report = export(
+            db.clone(),
+            collection.clone(),
+            &dir.join("root"),
+            &dir.join("staging"),
+            policy,
+            no_progress(),
+        )
+        .await;
+        assert!(!dir.join("staging").exists());
+        report
+    }
+
+    fn actions(report: &ExportReport) -> Vec<(&str, ExportAction)> {
+        report
+            .files
+            .iter()
+            .map(|file| (file.name.as_str(), file.action))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fail_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, collection, _tags) =
+            store_with(dir.path(), &[("a.txt", b"new a"), ("b.txt", b"new b")]).await;
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("b.txt"), b"old b").unwrap();
+
+        let error = export_with(&db, &collection, dir.path(), ConflictPolicy::Fail)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<SwiftsendError>(),
+            Some(SwiftsendError::DestinationExists { .. })
+        ));
+        assert!(!root.join("a.txt").exists());
+        assert_eq!(std::fs::read(root.join("b.txt")).unwrap(), b"old b");
+    }
+
+    #[tokio::test]
+    async fn overwrite_replaces_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, collection, _tags) =
+            store_with(dir.path(), &[("a.txt", b"new a"), ("b.txt", b"new b")]).await;
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("b.txt"), b"old b").unwrap();
+
+        let report = export_with(&db, &collection, dir.path(), ConflictPolicy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(
+            actions(&report),
+            [
+                ("a.txt", ExportAction::Written),
+                ("b.txt", ExportAction::Overwritten)
+            ]
+        );
+        assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), b"new a");
+        assert_eq!(std::fs::read(root.join("b.txt")).unwrap(), b"new b");
+    }
+
+    #[tokio::test]
+    async fn skip_identical_compares_with_the_store_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, collection, _tags) =
+            store_with(dir.path(), &[("a.txt", b"same"), ("b.txt", b"new b")]).await;
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("a.txt"), b"same").unwrap();
+
+        let report = export_with(&db, &collection, dir.path(), ConflictPolicy::SkipIdentical)
+            .await
+            .unwrap();
+        assert_eq!(
+            actions(&report),
+            [
+                ("a.txt", ExportAction::SkippedIdentical),
+                ("b.txt", ExportAction::Written)
+            ]
+        );
+
+        // a file with other content is a conflict, and nothing is written
+        std::fs::remove_file(root.join("b.txt")).unwrap();
+        std::fs::write(root.join("a.txt"), b"different").unwrap();
+        assert!(
+            export_with(&db, &collection, dir.path(), ConflictPolicy::SkipIdentical)
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), b"different");
+        assert!(!root.join("b.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn rename_skips_claimed_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, collection, _tags) = store_with(
+            dir.path(),
+            &[("report.pdf", b"new"), ("report (2).pdf", b"second")],
+        )
+        .await;
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("report.pdf"), b"old").unwrap();
+        std::fs::write(root.join("report (1).pdf"), b"old 1").unwrap();
+
+        let report = export_with(&db, &collection, dir.path(), ConflictPolicy::Rename)
+            .await
+            .unwrap();
+        // (1) exists on disk and (2) belongs to another entry
+        assert_eq!(report.files[0].path, root.join("report (3).pdf"));
+        assert_eq!(
+            actions(&report),
+            [
+                ("report.pdf", ExportAction::Renamed),
+                ("report (2).pdf", ExportAction::Written)
+            ]
+        );
+        assert_eq!(std::fs::read(root.join("report.pdf")).unwrap(), b"old");
+        assert_eq!(
+            std::fs::read(root.join("report (1).pdf")).unwrap(),
+            b"old 1"
+        );
+        assert_eq!(
+            std::fs::read(root.join("report (2).pdf")).unwrap(),
+            b"second"
+        );
+        assert_eq!(std::fs::read(root.join("report (3).pdf")).unwrap(), b"new");
+    }
+}
@@ -0,0 +1,374 @@
+//! Persistent record of finished and running transfers.
+//!
+//! The history is a single JSON file in the app data directory. It is small
+//! and rarely written, so every update simply rewrites the whole file.
+//! Receives that did not finish are tracked the same way in a second file, so
+//! they can be resumed after a restart.
+//!
+//! The app and the CLI share the files, so every access takes an exclusive
+//! lock on a third file next to them, on top of the lock within the process.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::error::SwiftsendError;
+
+const HISTORY_FILE: &str = "history.json";
+const INCOMPLETE_FILE: &str = "incomplete.json";
+const LOCK_FILE: &str = "history.lock";
+
+/// The identifier in `tauri.conf.json`, which names the app data directory.
+const APP_IDENTIFIER: &str = "com.swiftsend.app";
+
+/// Serializes read-modify-write cycles on the history files.
+static HISTORY_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HistoryEntry {
+    Send(SendRecord),
+    Receive(ReceiveRecord),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendRecord {
+    pub paths: Vec<PathBuf>,
+    /// The entry name of a share of in-memory data, which has no paths.
+    pub name: Option<String>,
+    /// Hex encoded hash of the shared collection.
+    pub hash: String,
+    pub ticket: String,
+    /// Seconds since the unix epoch.
+    pub created_at: u64,
+    /// Seconds since the unix epoch, `None` while the share is still running.
+    pub stopped_at: Option<u64>,
+    pub completed_downloads: u64,
+    /// The process serving the share, missing in entries written by older versions.
+    #[serde(default)]
+    pub pid: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiveRecord {
+    pub ticket: String,
+    pub destination: PathBuf,
+    /// Payload size in bytes, if the receive got far enough to learn it.
+    pub size: Option<u64>,
+    /// Seconds since the unix epoch.
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub result: TransferResult,
+}
+
+/// A receive whose partial store was kept so it can be resumed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncompleteDownload {
+    pub ticket: String,
+    /// Hex encoded hash of the collection being received.
+    pub hash: String,
+    pub destination: PathBuf,
+    /// Seconds since the unix epoch of the first attempt.
+    pub started_at: u64,
+    /// The error of the last attempt, `None` while an attempt is running.
+    pub last_error: Option<String>,
+}
+
+impl IncompleteDownload {
+    fn is(&self, hash: &str, destination: &Path) -> bool {
+        self.hash == hash && self.destination == destination
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TransferResult {
+    Completed,
+    Failed {
+        error: String,
+        /// The [`SwiftsendError::kind`], missing in entries written by older versions.
+        #[serde(default)]
+        kind: Option<String>,
+    },
+}
+
+impl<T> From<&Result<T, SwiftsendError>> for TransferResult {
+    fn from(result: &Result<T, SwiftsendError>) -> Self {
+        match result {
+            Ok(_) => TransferResult::Completed,
+            Err(error) => TransferResult::Failed {
+                error: error.to_string(),
+                kind: Some(error.kind().to_string()),
+            },
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// The directory Tauri resolves as the app data directory, for code that
+/// runs without the app, so the CLI and the app share one history.
+pub fn default_data_dir() -> anyhow::Result<PathBuf> {
+    let dir = dirs::data_dir().context("failed to resolve data dir")?;
+    Ok(dir.join(APP_IDENTIFIER))
+}
+
+/// The files the history is kept in.
+pub fn files(dir: &Path) -> [PathBuf; 2] {
+    [dir.join(HISTORY_FILE), dir.join(INCOMPLETE_FILE)]
+}
+
+/// Exclusive access to the history files of a directory, released on drop.
+struct HistoryGuard {
+    _process: MutexGuard<'static, ()>,
+    _file: std::fs::File,
+}
+
+/// Lock the history files in `dir` against this and every other process.
+async fn lock(dir: &Path) -> anyhow::Result<HistoryGuard> {
+    // threads of this process wait on the mutex rather than blocking on the file
+    let process = HISTORY_LOCK.lock().await;
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(LOCK_FILE);
+    let file = tokio::task::spawn_blocking(move || -> std::io::Result<std::fs::File> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        file.lock()?;
+        Ok(file)
+    })
+    .await?
+    .context("failed to lock history")?;
+    Ok(HistoryGuard {
+        _process: process,
+        _file: file,
+    })
+}
+
+/// Whether the process `pid` is still running.
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks that the process exists, EPERM means it does but
+    // belongs to someone else
+    unsafe { libc::kill(pid, 0) == 0 }
+    || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether the process `pid` is still running.
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, STILL_ACTIVE},
+        System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    };
+
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle.is_null() {
+        return false;
+    }
+    let mut code = 0;
+    let ok = unsafe { GetExitCodeProcess(handle, &mut code) };
+    unsafe { CloseHandle(handle) };
+    ok != 0 && code == STILL_ACTIVE as u32
+}
+
+async fn load<T: DeserializeOwned>(path: &PathBuf) -> anyhow::Result<Vec<T>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn store<T: Serialize>(path: &PathBuf, entries: &[T]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // write to a temp file first so a crash never leaves a truncated history
+    let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(entries)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Apply `f` to the entries stored in `file` and write the result back.
+async fn modify<T, F>(dir: &Path, file: &str, f: F) -> anyhow::Result<()>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce(&mut Vec<T>),
+{
+    let path = dir.join(file);
+    let _guard = lock(dir).await?;
+    let mut entries = load(&path).await?;
+    f(&mut entries);
+    store(&path, &entries).await
+}
+
+pub async fn record(dir: &Path, entry: HistoryEntry) -> anyhow::Result<()> {
+    modify(dir, HISTORY_FILE, |entries| entries.push(entry)).await
+}
+
+/// Update the send record for `ticket`, if there is one.
+pub async fn update_send<F>(dir: &Path, ticket: &str, f: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut SendRecord),
+{
+    modify(dir, HISTORY_FILE, |entries: &mut Vec<HistoryEntry>| {
+        let record = entries.iter_mut().rev().find_map(|entry| match entry {
+            HistoryEntry::Send(record) if record.ticket == ticket => Some(record),
+            _ => None,
+        });
+        if let Some(record) = record {
+            f(record);
+        }
+    })
+    .await
+}
+
+/// Mark every send that is still open as stopped, unless the process that
+/// serves it is still running.
+///
+/// Shares do not survive their process, so anything else left open was
+/// stopped when the app or the CLI exited. The shares of a CLI that is still
+/// running are left alone.
+pub async fn close_open_sends(dir: &Path) -> anyhow::Result<()> {
+    let now = unix_now();
+    modify(dir, HISTORY_FILE, |entries: &mut Vec<HistoryEntry>| {
+        for entry in entries.iter_mut() {
+            if let HistoryEntry::Send(record) = entry {
+                if record.stopped_at.is_none() && !record.pid.is_some_and(process_alive) {
+                    record.stopped_at = Some(now);
+                }
+            }
+        }
+    })
+    .await
+}
+
+pub async fn entries(dir: &Path) -> anyhow::Result<Vec<HistoryEntry>> {
+    let _guard = lock(dir).await?;
+    load(&dir.join(HISTORY_FILE)).await
+}
+
+pub async fn clear(dir: &Path) -> anyhow::Result<()> {
+    modify(dir, HISTORY_FILE, |entries: &mut Vec<HistoryEntry>| {
+        entries.clear()
+    })
+    .await
+}
+
+/// Insert or replace the incomplete download record for the same hash and destination.
+pub async fn save_incomplete(dir: &Path, download: IncompleteDownload) -> anyhow::Result<()> {
+    modify(
+        dir,
+        INCOMPLETE_FILE,
+        |entries: &mut Vec<IncompleteDownload>| match entries
+            .iter_mut()
+            .find(|entry| entry.is(&download.hash, &download.destination))
+        {
+            Some(entry) => *entry = download,
+            None => entries.push(download),
+        },
+    )
+    .await
+}
+
+pub async fn find_incomplete(
+    dir: &Path,
+    hash: &str,
+    destination: &Path,
+) -> anyhow::Result<Option<IncompleteDownload>> {
+    let _guard = lock(dir).await?;
+    let entries: Vec<IncompleteDownload> = load(&dir.join(INCOMPLETE_FILE)).await?;
+    Ok(entries
+        .into_iter()
+        .find(|entry| entry.is(hash, destination)))
+}
+
+pub async fn remove_incomplete(dir: &Path, hash: &str, destination: &Path) -> anyhow::Result<()> {
+    modify(
+        dir,
+        INCOMPLETE_FILE,
+        |entries: &mut Vec<IncompleteDownload>| {
+            entries.retain(|entry| !entry.is(hash, destination))
+        },
+    )
+    .await
+}
+
+pub async fn incomplete_downloads(dir: &Path) -> anyhow::Result<Vec<IncompleteDownload>> {
+    let _guard = lock(dir).await?;
+    load(&dir.join(INCOMPLETE_FILE)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(ticket: &str, pid: Option<u32>) -> HistoryEntry {
+        HistoryEntry::Send(SendRecord {
+            paths: Vec::new(),
+            name: None,
+            hash: String::new(),
+            ticket: ticket.to_string(),
+            created_at: 0,
+            stopped_at: None,
+            completed_downloads: 0,
+            pid,
+        })
+    }
+
+    #[tokio::test]
+    async fn closes_only_sends_of_exited_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        record(dir.path(), send("running", Some(std::process::id())))
+            .await
+            .unwrap();
+        record(dir.path(), send("exited", Some(u32::MAX)))
+            .await
+            .unwrap();
+        record(dir.path(), send("legacy", None)).await.unwrap();
+
+        close_open_sends(dir.path()).await.unwrap();
+
+        let stopped = entries(dir.path())
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|entry| match entry {
+                HistoryEntry::Send(record) => Some((record.ticket, record.stopped_at.is_some())),
+                HistoryEntry::Receive(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stopped,
+            [
+                ("running".to_string(), false),
+                ("exited".to_string(), true),
+                ("legacy".to_string(), true),
+            ]
+        );
+        // no temp files are left behind
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [HISTORY_FILE, LOCK_FILE]);
+    }
+}
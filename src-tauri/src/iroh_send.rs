@@ -1,58 +1,87 @@
-//! Command line arguments.
+//! Sharing and receiving collections.
+//!
+//! Nothing in here knows about Tauri. Transfers report through the
+//! [`ProgressSink`] of their [`TransferContext`], and the app commands in
+//! `commands.rs` are thin adapters over the functions here.
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
     path::{Component, Path, PathBuf},
     str::FromStr,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
     u64, usize,
 };
 
 use anyhow::{anyhow, Context};
-use console::style;
+use bao_tree::ChunkRanges;
 use data_encoding::HEXLOWER;
 use futures::{future::BoxFuture, TryFutureExt};
 use futures_buffered::BufferedStreamExt;
-use indicatif::{
-    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
-};
 use iroh::{
     discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher},
-    Endpoint, RelayMode, SecretKey,
+    endpoint::Connection,
+    Endpoint, NodeAddr, RelayMode, SecretKey,
 };
 use iroh_blobs::{
     format::collection::Collection,
     get::{
-        db::DownloadProgress,
-        fsm::{AtBlobHeaderNextError, DecodeError},
+        db::{BlobId, DownloadProgress},
+        error::GetError,
+        fsm::{self, AtBlobHeader, AtEndBlob, ConnectedNext, EndBlobNext},
         request::get_hash_seq_and_sizes,
     },
+    hashseq::HashSeq,
     net_protocol::Blobs,
+    protocol::{GetRequest, RangeSpecSeq},
     provider::{self, CustomEventSender},
-    store::{ExportMode, ImportMode, ImportProgress},
+    store::{BaoBatchWriter, ImportMode, ImportProgress, Map, MapEntry, MapEntryMut, MapMut},
     ticket::BlobTicket,
-    BlobFormat, HashAndFormat, TempTag,
+    util::progress::{
+        FallibleProgressBatchWriter, IdGenerator, IgnoreProgressSender, ProgressSender,
+    },
+    BlobFormat, Hash, HashAndFormat, TempTag,
 };
+use iroh_io::AsyncSliceReaderExt;
 use n0_future::{future::Boxed, StreamExt};
 use rand::Rng;
 use serde::Serialize;
-use tauri::{AppHandle, Emitter};
-use tokio::sync::Mutex;
 use walkdir::WalkDir;
 
-/// Send a file or directory between two machines, using blake3 verified streaming.
-///
-/// For all subcommands, you can specify a secret key using the IROH_SECRET
-/// environment variable. If you don't, a random one will be generated.
-///
-/// You can also specify a port for the magicsocket. If you don't, a random one
-/// will be chosen.
-///
+use crate::disk;
+use crate::error::SwiftsendError;
+use crate::export::{self, ConflictPolicy, ExportProgress, ExportReport, StagedExport};
+use crate::history::{self, HistoryEntry};
+use crate::manifest::{fetch_manifest, Manifest, Selection, MAX_HASH_SEQ_SIZE};
+use crate::progress::{
+    FetchTracker, ImportTracker, ProgressPhase, ProgressSink, ProgressTracker, Reconnecting,
+};
+use crate::retry::{self, Backoff, RetryPolicy};
+use crate::sanitize::{case_collisions, encode_os_str, normalize, restore_component};
+use crate::throttle::{self, RateLimiter, ThrottledProgress};
+use crate::transfers::{CancelHandle, TransferId};
+
+pub fn parse_ticket(ticket: &str) -> Result<BlobTicket, SwiftsendError> {
+    BlobTicket::from_str(ticket).map_err(|e| SwiftsendError::InvalidTicket(e.to_string()))
+}
 
 pub fn canonicalized_path_to_string(
     path: impl AsRef<Path>,
     must_be_relative: bool,
+) -> anyhow::Result<String> {
+    path_to_name(path, must_be_relative, true)
+}
+
+/// [`canonicalized_path_to_string`], with the components in normalization
+/// form C if `nfc` or as they are on disk otherwise.
+fn path_to_name(
+    path: impl AsRef<Path>,
+    must_be_relative: bool,
+    nfc: bool,
 ) -> anyhow::Result<String> {
     let mut path_str = String::new();
     let parts = path
@@ -60,13 +89,17 @@ pub fn canonicalized_path_to_string(
         .components()
         .filter_map(|c| match c {
             Component::Normal(x) => {
-                let c = match x.to_str() {
-                    Some(c) => c,
-                    None => return Some(Err(anyhow::anyhow!("invalid character in path"))),
-                };
+                // valid UTF-8 names go through the encoding as well, or one
+                // with characters from the escape range would be decoded
+                // into different bytes by the receiver
+                let c = encode_os_str(x);
 
-                if !c.contains('/') && !c.contains('\\') {
-                    Some(Ok(c))
+                // share files that were received with sanitized names
+                // under their original names
+                let c = restore_component(&c);
+                if !c.contains('/') && !c.contains('\\') && !c.chars().any(char::is_control) {
+                    let c = if nfc { normalize(&c) } else { c };
+                    Some(Ok(c.into_owned()))
                 } else {
                     Some(Err(anyhow::anyhow!("invalid path component {:?}", c)))
                 }
@@ -87,151 +120,137 @@ pub fn canonicalized_path_to_string(
     Ok(path_str)
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct UploadProgress {
-    progress: u64,
-    total: u64,
+/// How transfers find and reach the other side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Network {
+    /// Direct connections where possible and the n0 relays otherwise.
+    #[default]
+    Default,
+    /// Direct connections over the loopback interface only, with relays
+    /// disabled. For tests and for peers on the same machine.
+    Loopback,
 }
 
-#[tauri::command]
-pub async fn show_ingest_progress(
-    app: AppHandle,
-    recv: async_channel::Receiver<ImportProgress>,
-) -> anyhow::Result<()> {
-    let mp = MultiProgress::new();
-    mp.set_draw_target(ProgressDrawTarget::stderr());
-    let op = mp.add(ProgressBar::hidden());
-    op.set_style(
-        ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {msg}")?,
-    );
-    // op.set_message(format!("{} Ingesting ...\n", style("[1/2]").bold().dim()));
-    // op.set_length(total_files);
-    let mut names = BTreeMap::new();
-    let mut sizes = BTreeMap::new();
-    let mut pbs = BTreeMap::new();
-    let mut total_size: u64 = 0;
-    let mut total_progress: u64 = 0; // total progress for the entire upload
+impl Network {
+    fn endpoint(self, alpns: Vec<Vec<u8>>) -> iroh::endpoint::Builder {
+        let builder = Endpoint::builder()
+            .alpns(alpns)
+            .secret_key(SecretKey::generate(rand::rngs::OsRng));
+        match self {
+            Network::Default => builder.relay_mode(RelayMode::Default),
+            Network::Loopback => builder
+                .relay_mode(RelayMode::Disabled)
+                .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                .bind_addr_v6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0)),
+        }
+    }
 
-    let mut progress_bar = mp.add(ProgressBar::new(0)); // a single progress bar for the entire
+    async fn bind(self, alpns: Vec<Vec<u8>>) -> Result<Endpoint, SwiftsendError> {
+        self.endpoint(alpns)
+            .bind()
+            .await
+            .map_err(|e| SwiftsendError::Connection(e.to_string()))
+    }
 
-    loop {
-        let event = recv.recv().await;
-        match event {
-            Ok(ImportProgress::Found { id, name }) => {
-                println!("Found file: {} {}", id, name);
-                names.insert(id, name);
-            }
-            Ok(ImportProgress::Size { id, size }) => {
-                sizes.insert(id, size);
-                println!("Size: {} {}", id, size);
-                total_size = sizes.values().sum::<u64>();
-                println!("Total size: {}", total_size);
-                op.set_message(format!(
-                    "{} Ingesting {} files, {}\n",
-                    style("[1/2]").bold().dim(),
-                    sizes.len(),
-                    HumanBytes(total_size)
-                ));
-                app.emit(
-                    "upload_progress",
-                    UploadProgress {
-                        progress: total_progress,
-                        total: total_size,
-                    },
-                )
-                .unwrap();
-                let name = names.get(&id).cloned().unwrap_or_default();
-                let pb = mp.add(ProgressBar::hidden());
-                pb.set_style(ProgressStyle::with_template(
-                    "{msg}{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
-                )?.progress_chars("#>-"));
-                pb.set_message(format!("{} {}", style("[2/2]").bold().dim(), name));
-                pb.set_length(size);
-                pbs.insert(id, pb);
-            }
-            Ok(ImportProgress::OutboardProgress { id, offset }) => {
-                if let Some(pb) = pbs.get(&id) {
-                    total_progress += offset;
-                    pb.set_position(offset);
-                    println!("Progress: {} {}", id, offset);
-                    app.emit(
-                        "upload_progress",
-                        UploadProgress {
-                            progress: total_progress,
-                            total: total_size,
-                        },
-                    )
-                    .unwrap();
-                }
-            }
-            Ok(ImportProgress::OutboardDone { id, .. }) => {
-                // you are not guaranteed to get any OutboardProgress
-                if let Some(pb) = pbs.remove(&id) {
-                    println!("Done: {}", id);
-                    app.emit(
-                        "upload_progress",
-                        UploadProgress {
-                            progress: total_size,
-                            total: total_size,
-                        },
-                    )
-                    .unwrap();
-                    pb.finish_and_clear();
-                }
-            }
-            Ok(ImportProgress::CopyProgress { .. }) => {
-                // we are not copying anything
+    /// The address of `endpoint` to put into a ticket.
+    async fn ticket_addr(self, endpoint: &Endpoint) -> Result<NodeAddr, SwiftsendError> {
+        match self {
+            Network::Default => {
+                let _ = endpoint.home_relay().initialized().await;
+                endpoint
+                    .node_addr()
+                    .await
+                    .map_err(|e| SwiftsendError::Connection(e.to_string()))
             }
-            Err(e) => {
-                op.set_message(format!("Error receiving progress: {e}"));
-                break;
+            // there is no relay to wait for, and the bound socket is the
+            // only address that can be reached
+            Network::Loopback => {
+                let (v4, _v6) = endpoint.bound_sockets();
+                Ok(NodeAddr::new(endpoint.node_id()).with_direct_addresses([v4]))
             }
         }
     }
-    op.finish_and_clear();
-    Ok(())
 }
 
-#[tauri::command]
+/// What a transfer needs from whoever runs it.
+#[derive(Clone)]
+pub struct TransferContext {
+    /// Directory the transfer history is kept in.
+    pub data_dir: PathBuf,
+    /// Directory the blob stores of shares are created in.
+    pub store_dir: PathBuf,
+    pub network: Network,
+    pub sink: Arc<dyn ProgressSink>,
+}
+
+/// Forward the progress of the import of share `id` to `sink`.
+async fn report_import_progress(
+    sink: Arc<dyn ProgressSink>,
+    id: TransferId,
+    recv: async_channel::Receiver<ImportProgress>,
+) {
+    let mut tracker = ImportTracker::new(id);
+    loop {
+        let update = match recv.recv().await {
+            Ok(event) => tracker.update(event),
+            Err(_) => break,
+        };
+        if let Some(update) = update {
+            sink.import_progress(update);
+        }
+    }
+    if let Some(update) = tracker.finish() {
+        sink.import_progress(update);
+    }
+}
+
+/// Import the files under `paths` into a single collection. Every path is
+/// named relative to its parent, so the top level entries keep their names.
 async fn import(
-    app: AppHandle,
-    path: PathBuf,
+    sink: Arc<dyn ProgressSink>,
+    id: TransferId,
+    paths: Vec<PathBuf>,
     db: impl iroh_blobs::store::Store,
 ) -> anyhow::Result<(TempTag, u64, Collection)> {
-    let path = path.canonicalize()?;
-    anyhow::ensure!(path.exists(), "path {} does not exist", path.display());
-    let root = path.parent().context("context get parent")?;
-    // walkdir also works for files, so we don't need to special case them
-    let files = WalkDir::new(path.clone()).into_iter();
-    // flatten the directory structure into a list of (name, path) pairs.
-    // ignore symlinks.
-    let data_sources: Vec<(String, PathBuf)> = files
-        .map(|entry| {
-            let entry = entry?;
-            if !entry.file_type().is_file() {
-                // Skip symlinks. Directories are handled by WalkDir.
-                return Ok(None);
-            }
-            let path = entry.into_path();
-            let relative = path.strip_prefix(root)?;
-            let name = canonicalized_path_to_string(relative, true)?;
-            anyhow::Ok(Some((name, path)))
-        })
-        .filter_map(Result::transpose)
-        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut data_sources: Vec<(String, String, PathBuf)> = Vec::new();
+    for path in paths {
+        let path = path.canonicalize()?;
+        anyhow::ensure!(path.exists(), "path {} does not exist", path.display());
+        let root = path.parent().context("context get parent")?;
+        // walkdir also works for files, so we don't need to special case them
+        let files = WalkDir::new(path.clone()).into_iter();
+        // flatten the directory structure into a list of (name, path) pairs.
+        // ignore symlinks.
+        let sources = files
+            .map(|entry| {
+                let entry = entry?;
+                if !entry.file_type().is_file() {
+                    // Skip symlinks. Directories are handled by WalkDir.
+                    return Ok(None);
+                }
+                let path = entry.into_path();
+                let relative = path.strip_prefix(root)?;
+                let name = canonicalized_path_to_string(relative, true)?;
+                let raw = path_to_name(relative, true, false)?;
+                anyhow::Ok(Some((name, raw, path)))
+            })
+            .filter_map(Result::transpose)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        data_sources.extend(sources);
+    }
     let (send, recv) = async_channel::bounded(32);
     let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
-    let show_progress = tokio::spawn(show_ingest_progress(app, recv));
+    let show_progress = tokio::spawn(report_import_progress(sink, id, recv));
     // import all the files, using num_cpus workers, return names and temp tags
     let mut names_and_tags = futures_lite::stream::iter(data_sources)
-        .map(|(name, path)| {
+        .map(|(name, raw, path)| {
             let db = db.clone();
             let progress = progress.clone();
             async move {
                 let (temp_tag, file_size) = db
                     .import_file(path, ImportMode::TryReference, BlobFormat::Raw, progress)
                     .await?;
-                anyhow::Ok((name, temp_tag, file_size))
+                anyhow::Ok((name, raw, temp_tag, file_size))
             }
         })
         .buffered_unordered(num_cpus::get())
@@ -240,6 +259,28 @@ async fn import(
         .into_iter()
         .collect::<anyhow::Result<Vec<_>>>()?;
     drop(progress);
+    // raw names first, so the file whose name is in normalization form C on
+    // disk keeps it and its twins are the ones that change
+    names_and_tags.sort_by(|(a, a_raw, _, _), (b, b_raw, _, _)| {
+        a.cmp(b).then_with(|| (a != a_raw).cmp(&(b != b_raw)))
+    });
+    let mut taken = HashSet::new();
+    let mut names_and_tags = names_and_tags
+        .into_iter()
+        .map(|(name, raw, tag, size)| {
+            if taken.insert(name.clone()) {
+                return Ok((name, tag, size));
+            }
+            // two files whose names only differ in their unicode
+            // normalization, the twin keeps the name it has on disk
+            if raw != name && taken.insert(raw.clone()) {
+                tracing::warn!("{name:?} is shared under its unnormalized name {raw:?}");
+                return Ok((raw, tag, size));
+            }
+            // two shared paths with the same name
+            anyhow::bail!("more than one file is named {name:?}")
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
     names_and_tags.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
     // total size of all files
     let size = names_and_tags.iter().map(|(_, _, size)| *size).sum::<u64>();
@@ -253,145 +294,10 @@ async fn import(
     // now that the collection is stored, we can drop the tags
     // data is protected by the collection
     drop(tags);
-    show_progress.await??;
+    show_progress.await?;
     Ok((temp_tag, size, collection))
 }
 
-fn validate_path_component(component: &str) -> anyhow::Result<()> {
-    anyhow::ensure!(
-        !component.contains('/'),
-        "path components must not contain the only correct path separator, /"
-    );
-    Ok(())
-}
-
-fn get_export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
-    let parts = name.split('/');
-    let mut path = root.to_path_buf();
-    for part in parts {
-        validate_path_component(part)?;
-        path.push(part);
-    }
-    Ok(path)
-}
-
-async fn export(
-    db: impl iroh_blobs::store::Store,
-    collection: Collection,
-    path: &PathBuf,
-) -> anyhow::Result<()> {
-    println!("exporing data....");
-
-    let root = PathBuf::from(path);
-
-    for (name, hash) in collection.iter() {
-        let target = get_export_path(&root, name)?;
-        if target.exists() {
-            eprintln!(
-                "target {} already exists. Export stopped.",
-                target.display()
-            );
-            eprintln!("You can remove the file or directory and try again. The download will not be repeated.");
-            anyhow::bail!("target {} already exists", target.display());
-        }
-        db.export(
-            *hash,
-            target,
-            ExportMode::TryReference,
-            Box::new(move |_position| Ok(())),
-        )
-        .await?;
-    }
-    Ok(())
-}
-
-#[derive(Debug, Clone)]
-struct SendStatus {
-    /// the multiprogress bar
-    mp: MultiProgress,
-}
-
-impl SendStatus {
-    fn new() -> Self {
-        let mp = MultiProgress::new();
-        mp.set_draw_target(ProgressDrawTarget::stderr());
-        Self { mp }
-    }
-
-    fn new_client(&self) -> ClientStatus {
-        let current = self.mp.add(ProgressBar::hidden());
-        current.set_style(
-            ProgressStyle::default_spinner()
-                .template("{spinner:.green} [{elapsed_precise}] {msg}")
-                .unwrap(),
-        );
-        current.enable_steady_tick(Duration::from_millis(100));
-        current.set_message("waiting for requests");
-        ClientStatus {
-            current: current.into(),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-struct ClientStatus {
-    current: Arc<ProgressBar>,
-}
-
-impl Drop for ClientStatus {
-    fn drop(&mut self) {
-        if Arc::strong_count(&self.current) == 1 {
-            self.current.finish_and_clear();
-        }
-    }
-}
-
-impl CustomEventSender for ClientStatus {
-    fn send(&self, event: iroh_blobs::provider::Event) -> Boxed<()> {
-        self.try_send(event);
-        Box::pin(std::future::ready(()))
-    }
-
-    fn try_send(&self, event: provider::Event) {
-        tracing::info!("{:?}", event);
-        let msg = match event {
-            provider::Event::ClientConnected { connection_id } => {
-                Some(format!("{} got connection", connection_id))
-            }
-            provider::Event::TransferBlobCompleted {
-                connection_id,
-                hash,
-                index,
-                size,
-                ..
-            } => Some(format!(
-                "{} transfer blob completed {} {} {}",
-                connection_id,
-                hash,
-                index,
-                HumanBytes(size)
-            )),
-            provider::Event::TransferCompleted {
-                connection_id,
-                stats,
-                ..
-            } => Some(format!(
-                "{} transfer completed {} {}",
-                connection_id,
-                stats.send.write_bytes.size,
-                HumanDuration(stats.send.write_bytes.stats.duration)
-            )),
-            provider::Event::TransferAborted { connection_id, .. } => {
-                Some(format!("{} transfer completed", connection_id))
-            }
-            _ => None,
-        };
-        if let Some(msg) = msg {
-            self.current.set_message(msg);
-        }
-    }
-}
-
 // async fn send(args: SendArgs) -> anyhow::Result<SendResources> {
 //     let secret_key = get_or_create_secret(args.common.verbose > 0)?;
 //     // create a magicsocket endpoint
@@ -478,6 +384,108 @@ impl CustomEventSender for ClientStatus {
 //     })
 // }
 
+/// The children of the collection a running request has sent so far.
+#[derive(Debug, Default, Clone, Copy)]
+struct SentChildren {
+    metadata: bool,
+    files: bool,
+}
+
+/// Counts completed downloads of a share in the transfer history.
+///
+/// A receive sends several requests, for the sizes, the names and the data,
+/// and every one of them completes on its own. The sizes come with the last
+/// chunk of every child and the names with the metadata blob, which the
+/// receivers fetch before the data. So only a request that sends files but
+/// not the metadata blob is a download. An `inspect` never sends one, and
+/// neither do the requests of a receive that failed before the data was
+/// complete, however often it reconnected.
+#[derive(Debug, Clone)]
+struct ShareEvents {
+    data_dir: PathBuf,
+    /// The ticket is only known once the router is running, after the events are wired up.
+    ticket: Arc<OnceLock<String>>,
+    /// Running requests by connection and request id.
+    requests: Arc<std::sync::Mutex<HashMap<(u64, u64), SentChildren>>>,
+}
+
+impl ShareEvents {
+    fn new(data_dir: PathBuf) -> Self {
+        Self {
+            data_dir,
+            ticket: Default::default(),
+            requests: Default::default(),
+        }
+    }
+
+    /// Keep track of what requests send and tell whether `event` completes a download.
+    fn completes_download(&self, event: &provider::Event) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        match *event {
+            provider::Event::TransferBlobCompleted {
+                connection_id,
+                request_id,
+                index,
+                ..
+            } => {
+                let sent = requests.entry((connection_id, request_id)).or_default();
+                // index 0 is the metadata blob, the files follow
+                if index == 0 {
+                    sent.metadata = true;
+                } else {
+                    sent.files = true;
+                }
+                false
+            }
+            provider::Event::TransferCompleted {
+                connection_id,
+                request_id,
+                ..
+            } => requests
+                .remove(&(connection_id, request_id))
+                .is_some_and(|sent| sent.files && !sent.metadata),
+            provider::Event::TransferAborted {
+                connection_id,
+                request_id,
+                ..
+            } => {
+                requests.remove(&(connection_id, request_id));
+                false
+            }
+            _ => false,
+        }
+    }
+
+    async fn record_download(self) {
+        let Some(ticket) = self.ticket.get() else {
+            return;
+        };
+        let result = history::update_send(&self.data_dir, ticket, |record| {
+            record.completed_downloads += 1;
+        })
+        .await;
+        if let Err(e) = result {
+            tracing::warn!("failed to update history: {e}");
+        }
+    }
+}
+
+impl CustomEventSender for ShareEvents {
+    fn send(&self, event: provider::Event) -> Boxed<()> {
+        if self.completes_download(&event) {
+            Box::pin(self.clone().record_download())
+        } else {
+            Box::pin(std::future::ready(()))
+        }
+    }
+
+    fn try_send(&self, event: provider::Event) {
+        if self.completes_download(&event) {
+            tokio::spawn(self.clone().record_download());
+        }
+    }
+}
+
 // Helper function to wrap the connection in a thread-safe way
 fn wrap_connection<T: Clone + Send + Sync + 'static>(
     conn: T,
@@ -489,262 +497,947 @@ fn wrap_connection<T: Clone + Send + Sync + 'static>(
     }
 }
 
+/// A share that is being served.
+///
+/// The collection stays available until [`Share::stop`] is called.
 #[derive(Debug)]
-struct SendResources {
-    blobs_data_dir: PathBuf,
+pub struct Share {
+    /// `None` for shares of in-memory data.
+    blobs_data_dir: Option<PathBuf>,
     router: iroh::protocol::Router,
     ticket: BlobTicket,
+    case_collisions: Vec<(String, String)>,
 }
 
-static SEND_RESOURCES: Mutex<Option<SendResources>> = Mutex::const_new(None);
+impl Share {
+    pub fn ticket(&self) -> &BlobTicket {
+        &self.ticket
+    }
+
+    /// Pairs of shared names that only differ in case. A receiver on a
+    /// case-insensitive filesystem has to rename one of each pair.
+    pub fn case_collisions(&self) -> &[(String, String)] {
+        &self.case_collisions
+    }
+
+    /// The blob store the share is served from, unless it is kept in memory.
+    pub fn blobs_data_dir(&self) -> Option<&Path> {
+        self.blobs_data_dir.as_deref()
+    }
 
-#[tauri::command]
-pub async fn send_files(app: AppHandle, path: String) -> anyhow::Result<String, String> {
-    let secret_key = SecretKey::generate(rand::rngs::OsRng);
-    let mut builder = Endpoint::builder()
-        .alpns(vec![iroh_blobs::protocol::ALPN.to_vec()])
-        .secret_key(secret_key)
-        .relay_mode(RelayMode::Default);
+    /// Stop serving, mark the share as stopped in the history in `data_dir`
+    /// and remove its store.
+    pub async fn stop(self, data_dir: &Path) -> Result<(), SwiftsendError> {
+        tracing::info!("shutting down share");
+
+        let ticket = self.ticket.to_string();
+        let result = history::update_send(data_dir, &ticket, |record| {
+            record.stopped_at = Some(history::unix_now());
+        })
+        .await;
+        if let Err(e) = result {
+            tracing::warn!("failed to update history: {e}");
+        }
 
+        tokio::time::timeout(Duration::from_secs(2), self.router.shutdown())
+            .await
+            .map_err(|e| SwiftsendError::Other(e.to_string()))??;
+
+        if let Some(blobs_data_dir) = self.blobs_data_dir {
+            tokio::fs::remove_dir_all(blobs_data_dir).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the app and the CLI keep the blob stores of shares.
+pub fn default_store_dir() -> PathBuf {
+    // servers without a desktop session usually have no download directory
+    dirs::download_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(".sendme")
+}
+
+/// Import `paths` into a fresh store and start serving them.
+///
+/// `cancel` stops the import. Once it is done the returned [`Share`] has
+/// the ticket and keeps serving until it is stopped.
+pub async fn share(
+    ctx: &TransferContext,
+    id: TransferId,
+    paths: Vec<PathBuf>,
+    cancel: &CancelHandle,
+) -> Result<Share, SwiftsendError> {
     let suffix = rand::thread_rng().gen::<[u8; 16]>();
     // let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
-    let download_dir = dirs::download_dir().ok_or_else(|| "No document directory".to_string())?;
-    let sendme_dir = download_dir.join(".sendme");
+    let sendme_dir = &ctx.store_dir;
     let blobs_data_dir = sendme_dir.join(format!(".sendme-send-{}", HEXLOWER.encode(&suffix)));
     if blobs_data_dir.exists() {
-        return Err("Cannot share twice from the same directory".to_string());
+        return Err(SwiftsendError::Other(
+            "Cannot share twice from the same directory".to_string(),
+        ));
     }
 
-    tokio::fs::create_dir_all(&blobs_data_dir)
-        .await
-        .map_err(|e| e.to_string())?;
+    tokio::fs::create_dir_all(&blobs_data_dir).await?;
 
-    let endpoint = builder.bind().await.map_err(|e| e.to_string())?;
+    let endpoint = ctx
+        .network
+        .bind(vec![iroh_blobs::protocol::ALPN.to_vec()])
+        .await?;
+    let events = ShareEvents::new(ctx.data_dir.clone());
     let blobs = Blobs::persistent(&blobs_data_dir)
         .await
-        .map_err(|e| e.to_string())?
+        .map_err(|e| SwiftsendError::Store(e.to_string()))?
+        .events(events.clone().into())
+        .build(&endpoint);
+
+    let mut case_collisions = Vec::new();
+    let importing = import(ctx.sink.clone(), id, paths.clone(), blobs.store().clone()).map_ok(
+        |(temp_tag, _size, collection)| {
+            case_collisions = collection_case_collisions(&collection);
+            temp_tag
+        },
+    );
+    let served = serve(ctx, endpoint, blobs, events, importing, cancel).await;
+    let (router, ticket) = match served {
+        Ok(served) => served,
+        Err(e) => {
+            if let Err(e) = tokio::fs::remove_dir_all(&blobs_data_dir).await {
+                tracing::warn!("failed to remove store of aborted share: {e}");
+            }
+            return Err(e);
+        }
+    };
+    record_share(ctx, &ticket, paths, None).await;
+
+    Ok(Share {
+        blobs_data_dir: Some(blobs_data_dir),
+        router,
+        ticket,
+        case_collisions,
+    })
+}
+
+/// Pairs of entries of `collection` that would be the same file on a
+/// case-insensitive filesystem.
+fn collection_case_collisions(collection: &Collection) -> Vec<(String, String)> {
+    case_collisions(collection.iter().map(|(name, _hash)| name.as_str()))
+        .into_iter()
+        .map(|(a, b)| {
+            tracing::warn!("{a:?} and {b:?} collide on case-insensitive filesystems");
+            (a.to_string(), b.to_string())
+        })
+        .collect()
+}
+
+/// Share `data` as a collection with a single entry called `name`.
+///
+/// The data is imported straight from memory and the store is kept in
+/// memory as well, so nothing is written to disk.
+pub async fn share_bytes(
+    ctx: &TransferContext,
+    id: TransferId,
+    name: String,
+    data: Vec<u8>,
+    cancel: &CancelHandle,
+) -> Result<Share, SwiftsendError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(SwiftsendError::InvalidName {
+            name,
+            reason: "must be a single, non-empty path component".to_string(),
+        });
+    }
+    let endpoint = ctx
+        .network
+        .bind(vec![iroh_blobs::protocol::ALPN.to_vec()])
+        .await?;
+    let events = ShareEvents::new(ctx.data_dir.clone());
+    let blobs = Blobs::memory()
+        .events(events.clone().into())
         .build(&endpoint);
 
+    let store = blobs.store().clone();
+    let entry = name.clone();
+    let importing = async move {
+        let tag = store.import_bytes(data.into(), BlobFormat::Raw).await?;
+        let collection = std::iter::once((entry, *tag.hash())).collect::<Collection>();
+        let temp_tag = collection.store(&store).await?;
+        drop(tag);
+        anyhow::Ok(temp_tag)
+    };
+    let (router, ticket) = serve(ctx, endpoint, blobs, events, importing, cancel).await?;
+    record_share(ctx, &ticket, Vec::new(), Some(name)).await;
+
+    Ok(Share {
+        blobs_data_dir: None,
+        router,
+        ticket,
+        case_collisions: Vec::new(),
+    })
+}
+
+/// Run `import` and serve the collection it stores in `blobs`.
+///
+/// `cancel` stops the import, in which case the router is shut down again.
+async fn serve<S: iroh_blobs::store::Store>(
+    ctx: &TransferContext,
+    endpoint: Endpoint,
+    blobs: Blobs<S>,
+    events: ShareEvents,
+    import: impl std::future::Future<Output = anyhow::Result<TempTag>>,
+    cancel: &CancelHandle,
+) -> Result<(iroh::protocol::Router, BlobTicket), SwiftsendError> {
     let router = iroh::protocol::Router::builder(endpoint)
-        .accept(iroh_blobs::ALPN, blobs.clone())
+        .accept(iroh_blobs::ALPN, blobs)
         .spawn()
-        .await
-        .map_err(|e| e.to_string())?;
+        .await?;
 
-    let path = PathBuf::from(path);
-    let (temp_tag, _size, _collection) = import(app, path, blobs.store().clone())
-        .await
-        .map_err(|e| e.to_string())?;
+    let imported = tokio::select! {
+        result = import => result.map_err(SwiftsendError::from),
+        _ = cancel.cancelled() => Err(SwiftsendError::Cancelled),
+    };
+    // the import future has been dropped at this point, taking the workers
+    // and the temp tags of everything imported so far with it
+    let temp_tag = match imported {
+        Ok(temp_tag) => temp_tag,
+        Err(e) => {
+            if let Err(e) = tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await {
+                tracing::warn!("failed to shut down router: {e}");
+            }
+            return Err(e);
+        }
+    };
 
     let hash = *temp_tag.hash();
-    let _ = router.endpoint().home_relay().initialized().await;
-    let addr = router
-        .endpoint()
-        .node_addr()
-        .await
-        .map_err(|e| e.to_string())?;
-    let ticket = BlobTicket::new(addr, hash, BlobFormat::HashSeq).map_err(|e| e.to_string())?;
+    let addr = ctx.network.ticket_addr(router.endpoint()).await?;
+    let ticket = BlobTicket::new(addr, hash, BlobFormat::HashSeq)?;
+    let _ = events.ticket.set(ticket.to_string());
+    Ok((router, ticket))
+}
 
-    let resources = SendResources {
-        blobs_data_dir,
-        router,
-        ticket: ticket.clone(),
+async fn record_share(
+    ctx: &TransferContext,
+    ticket: &BlobTicket,
+    paths: Vec<PathBuf>,
+    name: Option<String>,
+) {
+    let record = history::SendRecord {
+        paths,
+        name,
+        hash: ticket.hash().to_hex(),
+        ticket: ticket.to_string(),
+        created_at: history::unix_now(),
+        stopped_at: None,
+        completed_downloads: 0,
+        pid: Some(std::process::id()),
     };
+    if let Err(e) = history::record(&ctx.data_dir, HistoryEntry::Send(record)).await {
+        tracing::warn!("failed to record share in history: {e}");
+    }
+}
 
-    *SEND_RESOURCES.lock().await = Some(resources);
-    Ok(ticket.to_string())
+/// Turn the per blob events of a fetch into [`ProgressUpdate`](crate::progress::ProgressUpdate)s.
+async fn report_download_progress(
+    sink: Arc<dyn ProgressSink>,
+    recv: async_channel::Receiver<DownloadProgress>,
+    mut fetch: FetchTracker,
+    tracker: Arc<std::sync::Mutex<ProgressTracker>>,
+) {
+    while let Ok(event) = recv.recv().await {
+        let Some((progress, file)) = fetch.update(event) else {
+            continue;
+        };
+        let update = tracker.lock().unwrap().update(
+            ProgressPhase::Fetching,
+            progress,
+            fetch.total(),
+            Some(file),
+        );
+        if let Some(update) = update {
+            sink.download_progress(update);
+        }
+    }
 }
 
-#[tauri::command]
-pub async fn shutdown() -> anyhow::Result<(), String> {
-    if let Some(resources) = SEND_RESOURCES.lock().await.take() {
-        println!("shutting down");
+/// Settings of a single receive.
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveOptions {
+    pub limit: Option<u64>,
+    pub retry: RetryPolicy,
+    pub conflict: ConflictPolicy,
+    /// Receive only the matching entries instead of the whole collection.
+    pub select: Option<Selection>,
+}
 
-        tokio::time::timeout(Duration::from_secs(2), resources.router.shutdown())
-            .await
-            .map_err(|e| e.to_string())?
-            .map_err(|e| e.to_string())?;
+/// The outcome of a completed receive.
+#[derive(Debug)]
+pub struct Received {
+    /// Payload size in bytes.
+    pub size: u64,
+    pub report: ExportReport,
+}
 
-        tokio::fs::remove_dir_all(resources.blobs_data_dir)
-            .await
-            .map_err(|e| e.to_string())?;
+/// Receive `ticket` into `destination`, keeping the history up to date.
+///
+/// If the receive fails, or is cancelled with `keep_partial`, the partial
+/// store is kept and the download is recorded as incomplete. Receiving the
+/// same ticket into the same destination again only fetches what is missing.
+pub async fn receive(
+    ctx: &TransferContext,
+    id: TransferId,
+    received_ticket: BlobTicket,
+    destination: PathBuf,
+    options: ReceiveOptions,
+    cancel: &CancelHandle,
+) -> Result<Received, SwiftsendError> {
+    let ticket = received_ticket.to_string();
+    let hash = received_ticket.hash();
+    let iroh_data_dir = get_store_dir(&destination, &hash);
+    let started_at = history::unix_now();
+    let start = Instant::now();
+
+    let previous = history::find_incomplete(&ctx.data_dir, &hash.to_hex(), &destination).await;
+    let mut incomplete = history::IncompleteDownload {
+        ticket: ticket.clone(),
+        hash: hash.to_hex(),
+        destination: destination.clone(),
+        started_at: match previous {
+            Ok(Some(previous)) => previous.started_at,
+            _ => started_at,
+        },
+        last_error: None,
+    };
+    if let Err(e) = history::save_incomplete(&ctx.data_dir, incomplete.clone()).await {
+        tracing::warn!("failed to record incomplete download: {e}");
+    }
+
+    let limiter = throttle::register(id, options.limit).await;
+    let result = tokio::select! {
+        result = download(
+            ctx,
+            id,
+            received_ticket,
+            destination.clone(),
+            limiter,
+            options,
+            cancel,
+        ) => result,
+        _ = cancel.cancelled() => Err(SwiftsendError::Cancelled),
+    };
+    throttle::unregister(id).await;
+    // the renames into the destination can't be stopped half way, so the
+    // commit is not raced against a cancel. One that came in while the last
+    // file was staged still counts.
+    let result = match result {
+        Ok(_) if cancel.is_cancelled() => Err(SwiftsendError::Cancelled),
+        Ok(downloaded) => downloaded.commit(&iroh_data_dir).await,
+        Err(e) => Err(e),
+    };
+
+    let cancelled = result.is_err() && cancel.is_cancelled();
+    let discard = cancelled && !cancel.keep_partial();
+    if discard {
+        if let Err(e) = tokio::fs::remove_dir_all(&iroh_data_dir).await {
+            tracing::warn!("failed to remove partial download: {e}");
+        }
+    }
+    // a cancelled export never gets to clean up after itself. The staging
+    // directory only holds copies, the store keeps the data for a resume.
+    let staging = get_staging_dir(&destination, &hash);
+    if result.is_err() && staging.exists() {
+        if let Err(e) = tokio::fs::remove_dir_all(&staging).await {
+            tracing::warn!("failed to remove staging directory: {e}");
+        }
+    }
+    let incomplete_result = match &result {
+        Ok(_) => history::remove_incomplete(&ctx.data_dir, &incomplete.hash, &destination).await,
+        Err(_) if discard => {
+            history::remove_incomplete(&ctx.data_dir, &incomplete.hash, &destination).await
+        }
+        Err(e) => {
+            incomplete.last_error = Some(e.to_string());
+            history::save_incomplete(&ctx.data_dir, incomplete).await
+        }
+    };
+    if let Err(e) = incomplete_result {
+        tracing::warn!("failed to update incomplete download: {e}");
+    }
+
+    let record = history::ReceiveRecord {
+        ticket,
+        destination,
+        size: result.as_ref().ok().map(|received| received.size),
+        started_at,
+        duration_ms: start.elapsed().as_millis() as u64,
+        result: (&result).into(),
+    };
+    if let Err(e) = history::record(&ctx.data_dir, HistoryEntry::Receive(record)).await {
+        tracing::warn!("failed to record receive in history: {e}");
     }
+    result
+}
+
+/// Whether a receive of `ticket` into `destination` stopped half way and can
+/// be picked up again.
+pub async fn is_resumable(
+    data_dir: &Path,
+    ticket: &BlobTicket,
+    destination: &Path,
+) -> Result<bool, SwiftsendError> {
+    let hash = ticket.hash();
+    let incomplete = history::find_incomplete(data_dir, &hash.to_hex(), destination).await?;
+    Ok(incomplete.is_some() && get_store_dir(destination, &hash).exists())
+}
+
+/// Delete the partial store of an incomplete receive and forget about it.
+pub async fn discard_incomplete(
+    data_dir: &Path,
+    ticket: &BlobTicket,
+    destination: &Path,
+) -> Result<(), SwiftsendError> {
+    let hash = ticket.hash();
+    let iroh_data_dir = get_store_dir(destination, &hash);
+    if iroh_data_dir.exists() {
+        tokio::fs::remove_dir_all(&iroh_data_dir).await?;
+    }
+    history::remove_incomplete(data_dir, &hash.to_hex(), destination).await?;
     Ok(())
 }
 
-#[derive(Clone, Serialize)]
-struct ProgressUpdate {
-    progress: u64,
-    total: u64,
+/// The blob store a receive of `hash` into `receive_path` downloads into.
+fn get_store_dir(receive_path: &Path, hash: &Hash) -> PathBuf {
+    receive_path
+        .join(".sendme")
+        .join(format!(".sendme-get-{}", hash.to_hex()))
 }
 
-#[tauri::command]
-async fn send_download_progress(
-    app: AppHandle,
-    recv: async_channel::Receiver<DownloadProgress>,
-    total_size: u64,
-    total_files: usize,
-) -> anyhow::Result<()> {
-    let mut total_done = 0;
-    let mut sizes = BTreeMap::new();
-    let mut last_offsets = BTreeMap::new();
+/// The directory a receive of `hash` into `receive_path` exports into before
+/// moving the files into place. It is inside `receive_path`, so the final
+/// renames never cross a filesystem boundary.
+fn get_staging_dir(receive_path: &Path, hash: &Hash) -> PathBuf {
+    receive_path
+        .join(".sendme")
+        .join(format!(".sendme-export-{}", hash.to_hex()))
+}
 
-    loop {
-        let x = recv.recv().await;
-        match x {
-            Ok(DownloadProgress::Connected) => {
-                println!("Connected to the server");
-                app.emit(
-                    "download_progress",
-                    ProgressUpdate {
-                        progress: 0,
-                        total: total_size,
-                    },
-                )
-                .unwrap();
-            }
-            Ok(DownloadProgress::FoundHashSeq { children, .. }) => {
-                // println!("Found hash sequence: {:?}", children);
-            }
-            Ok(DownloadProgress::Found { id, size, .. }) => {
-                sizes.insert(id, size);
-                last_offsets.insert(id, 0u64);
-            }
-            Ok(DownloadProgress::Progress { id, offset, .. }) => {
-                let old_offset = last_offsets.get(&id).copied().unwrap_or(0);
-                let diff = offset.saturating_sub(old_offset);
-                total_done += diff;
-
-                last_offsets.insert(id, offset);
-
-                println!("Progress: {}", total_done);
-                // println!("Progress: {}", total_progress);
-                app.emit(
-                    "download_progress",
-                    ProgressUpdate {
-                        progress: total_done,
-                        total: total_size,
-                    },
-                )
-                .unwrap();
+/// Fetches blobs from the sender, reconnecting according to the retry policy.
+///
+/// Every attempt after a dropped connection starts on a fresh one and only
+/// fetches what is not in the store yet.
+struct Fetcher {
+    sink: Arc<dyn ProgressSink>,
+    id: TransferId,
+    endpoint: Endpoint,
+    addr: NodeAddr,
+    connection: Option<Connection>,
+    backoff: Backoff,
+    /// Writes of the download so far, from [`ThrottledProgress::writes`].
+    writes: Arc<AtomicU64>,
+    /// `writes` at the previous failure.
+    writes_at_failure: u64,
+}
+
+impl Fetcher {
+    /// Wait out the next backoff delay after `error`, or give up with it.
+    ///
+    /// A failure after the download made progress starts a new outage with
+    /// the full retry policy.
+    async fn reconnect_delay(&mut self, error: SwiftsendError) -> Result<(), SwiftsendError> {
+        self.connection = None;
+        let writes = self.writes.load(Ordering::Relaxed);
+        if writes > self.writes_at_failure {
+            self.backoff.reset();
+        }
+        self.writes_at_failure = writes;
+        let Some(delay) = self.backoff.next_delay() else {
+            return Err(error);
+        };
+        let attempt = self.backoff.attempt();
+        tracing::info!(
+            "receive {}: reconnecting (attempt {attempt}) in {delay:?}: {error}",
+            self.id
+        );
+        let event = Reconnecting {
+            id: self.id,
+            attempt,
+            delay_ms: delay.as_millis() as u64,
+            error: error.to_string(),
+        };
+        self.sink.reconnecting(event);
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+
+    async fn connection(&mut self) -> Result<Connection, SwiftsendError> {
+        loop {
+            if let Some(connection) = &self.connection {
+                return Ok(connection.clone());
             }
-            Ok(DownloadProgress::Done { id }) => {
-                last_offsets.remove(&id);
-                sizes.remove(&id).unwrap_or_default();
-                println!("Done: {}", id);
-                if (total_files + 1 == id as usize) {
-                    app.emit(
-                        "download_progress",
-                        ProgressUpdate {
-                            progress: total_size,
-                            total: total_size,
-                        },
-                    )
-                    .unwrap();
+            match self
+                .endpoint
+                .connect(self.addr.clone(), iroh_blobs::protocol::ALPN)
+                .await
+            {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    let error = SwiftsendError::Connection(e.to_string());
+                    self.reconnect_delay(error).await?
                 }
             }
-            Ok(DownloadProgress::AllDone(stats)) => {
-                println!("All done: {:?}", stats);
-                app.emit(
-                    "download_progress",
-                    ProgressUpdate {
-                        progress: total_size,
-                        total: total_size,
-                    },
-                )
-                .unwrap();
-                break;
+        }
+    }
+
+    async fn hash_seq_and_sizes(
+        &mut self,
+        hash: &Hash,
+    ) -> Result<(HashSeq, Arc<[u64]>), SwiftsendError> {
+        loop {
+            let connection = self.connection().await?;
+            let result = get_hash_seq_and_sizes(&connection, hash, MAX_HASH_SEQ_SIZE)
+                .await
+                .map_err(retry::get_error);
+            match result {
+                Ok(res) => return Ok(res),
+                Err(e) if retry::is_retryable(&e) => self.reconnect_delay(e.into()).await?,
+                Err(e) => return Err(e.into()),
             }
-            Ok(DownloadProgress::Abort(e)) => {
-                println!("download  aborted: {:?}", e);
-                anyhow::bail!("Download aborted");
+        }
+    }
+
+    async fn fetch<P>(
+        &mut self,
+        db: &iroh_blobs::store::fs::Store,
+        target: HashAndFormat,
+        progress: P,
+    ) -> Result<(), SwiftsendError>
+    where
+        P: ProgressSender<Msg = DownloadProgress> + IdGenerator,
+    {
+        loop {
+            let connection = self.connection().await?;
+            let get_conn = || async move { Ok(connection) };
+            match iroh_blobs::get::db::get_to_db(db, get_conn, &target, progress.clone()).await {
+                Ok(_stats) => return Ok(()),
+                Err(e) if retry::is_retryable(&e) => self.reconnect_delay(e.into()).await?,
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                anyhow::bail!("Error receiving progress: {}", e);
+        }
+    }
+
+    /// Fetch the children of `root` at `children`, indices into `hash_seq`,
+    /// with one request per connection.
+    async fn fetch_children<P>(
+        &mut self,
+        db: &iroh_blobs::store::fs::Store,
+        root: Hash,
+        hash_seq: &HashSeq,
+        children: &[usize],
+        progress: P,
+    ) -> Result<(), SwiftsendError>
+    where
+        P: ProgressSender<Msg = DownloadProgress> + IdGenerator,
+    {
+        loop {
+            let connection = self.connection().await?;
+            let result =
+                get_children_to_db(db, connection, root, hash_seq, children, progress.clone())
+                    .await;
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if retry::is_retryable(&e) => self.reconnect_delay(e.into()).await?,
+                Err(e) => return Err(e.into()),
             }
-            _ => {}
         }
     }
+}
+
+/// The chunks of `hash` that are not in the store yet.
+async fn missing_ranges(
+    db: &iroh_blobs::store::fs::Store,
+    hash: &Hash,
+) -> Result<ChunkRanges, GetError> {
+    let entry = db
+        .get_mut(hash)
+        .await
+        .map_err(|e| GetError::LocalFailure(e.into()))?;
+    match entry {
+        Some(entry) if entry.is_complete() => Ok(ChunkRanges::empty()),
+        Some(entry) => {
+            let valid = iroh_blobs::get::db::valid_ranges::<iroh_blobs::store::fs::Store>(&entry)
+                .await
+                .map_err(GetError::LocalFailure)?;
+            Ok(ChunkRanges::all().difference(&valid))
+        }
+        None => Ok(ChunkRanges::all()),
+    }
+}
+
+/// Like `get_to_db` for a hash sequence, but only for the children at
+/// `children`. A single request asks for what is missing of all of them, so
+/// a selection of many small files doesn't pay a round trip per file.
+async fn get_children_to_db<P>(
+    db: &iroh_blobs::store::fs::Store,
+    connection: Connection,
+    root: Hash,
+    hash_seq: &HashSeq,
+    children: &[usize],
+    progress: P,
+) -> Result<(), GetError>
+where
+    P: ProgressSender<Msg = DownloadProgress> + IdGenerator,
+{
+    let child = |index: usize| {
+        hash_seq.get(index).ok_or_else(|| {
+            GetError::NoncompliantNode(anyhow!("hash sequence has no child {index}"))
+        })
+    };
+    // the ranges of the request start with the root, the children follow
+    let mut ranges = Vec::new();
+    for &index in children {
+        let missing = missing_ranges(db, &child(index)?).await?;
+        if ranges.len() <= index + 1 {
+            ranges.resize(index + 2, ChunkRanges::empty());
+        }
+        ranges[index + 1] = missing;
+    }
+    if ranges.iter().all(|missing| missing.is_empty()) {
+        return Ok(());
+    }
+    let request = GetRequest::new(root, RangeSpecSeq::from_ranges(ranges));
+    let connected = fsm::start(connection, request).next().await?;
+    let mut next = match connected.next().await? {
+        ConnectedNext::StartChild(start) => start,
+        ConnectedNext::Closing(closing) => {
+            closing.next().await?;
+            return Ok(());
+        }
+        ConnectedNext::StartRoot(_) => {
+            return Err(GetError::NoncompliantNode(anyhow!(
+                "sent the root, which was not requested"
+            )))
+        }
+    };
+    let closing = loop {
+        let index = usize::try_from(next.child_offset()).unwrap_or(usize::MAX);
+        let header = next.next(child(index)?);
+        let end = get_blob_to_db(db, header, progress.clone()).await?;
+        match end.next() {
+            EndBlobNext::MoreChildren(start) => next = start,
+            EndBlobNext::Closing(closing) => break closing,
+        }
+    };
+    closing.next().await?;
     Ok(())
 }
 
-#[tauri::command]
-pub async fn receive_files(
-    app: AppHandle,
-    ticket: String,
-    path: String,
-) -> anyhow::Result<(), String> {
-    tokio::task::spawn_blocking(move || {
-        let rt = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
-        rt.block_on(async move {
-            let received_ticket = BlobTicket::from_str(&ticket).map_err(|e| e.to_string())?;
-            println!("Received ticket: {}", received_ticket.to_string());
-            let addr = received_ticket.node_addr().clone();
-            let secret_key = SecretKey::generate(rand::rngs::OsRng);
+/// Write one blob of a response into the store, reporting progress the way
+/// `get_to_db` does.
+async fn get_blob_to_db<P>(
+    db: &iroh_blobs::store::fs::Store,
+    header: AtBlobHeader,
+    progress: P,
+) -> Result<AtEndBlob, GetError>
+where
+    P: ProgressSender<Msg = DownloadProgress> + IdGenerator,
+{
+    let (content, size) = header.next().await?;
+    let hash = content.hash();
+    let entry = db.get_or_create(hash, size).await?;
+    let writer = entry.batch_writer().await?;
+    let id = progress.new_id();
+    let child = std::num::NonZeroU64::new(content.offset()).map_or(BlobId::Root, BlobId::Child);
+    progress
+        .send(DownloadProgress::Found {
+            id,
+            hash,
+            size,
+            child,
+        })
+        .await?;
+    let sender = progress.clone();
+    let on_write = move |offset: u64, _length: usize| {
+        sender.try_send(DownloadProgress::Progress { id, offset })
+    };
+    let mut writer = FallibleProgressBatchWriter::new(writer, on_write);
+    let end = content.write_all_batch(&mut writer).await?;
+    writer.sync().await?;
+    drop(writer);
+    db.insert_complete(entry).await?;
+    progress.send(DownloadProgress::Done { id }).await?;
+    Ok(end)
+}
 
-            let builder = Endpoint::builder()
-                .alpns(vec![])
-                .secret_key(secret_key)
-                .relay_mode(RelayMode::Default);
+async fn download(
+    ctx: &TransferContext,
+    id: TransferId,
+    received_ticket: BlobTicket,
+    receive_path: PathBuf,
+    limiter: Arc<RateLimiter>,
+    options: ReceiveOptions,
+    cancel: &CancelHandle,
+) -> Result<Downloaded, SwiftsendError> {
+    let sink = ctx.sink.clone();
+    let iroh_data_dir = get_store_dir(&receive_path, &received_ticket.hash());
+    let addr = received_ticket.node_addr().clone();
+    let endpoint = ctx.network.bind(vec![]).await?;
 
-            let receive_path = PathBuf::from(path);
+    let db = iroh_blobs::store::fs::Store::load(&iroh_data_dir)
+        .await
+        .map_err(|e| SwiftsendError::Store(e.to_string()))?;
 
-            let sendme_dir = receive_path.join(".sendme");
+    let hash_and_format = HashAndFormat {
+        hash: received_ticket.hash(),
+        format: received_ticket.format(),
+    };
 
-            let endpoint = builder.bind().await.map_err(|e| e.to_string())?;
-            let dir_name = format!(".sendme-get-{}", received_ticket.hash().to_hex());
-            let iroh_data_dir = sendme_dir.join(dir_name);
+    let (send, recv) = async_channel::bounded(32);
+    let progress = ThrottledProgress::new(
+        iroh_blobs::util::progress::AsyncChannelProgressSender::new(send),
+        limiter,
+        cancel.clone(),
+    );
 
-            let db = iroh_blobs::store::fs::Store::load(&iroh_data_dir)
-                .await
-                .map_err(|e| e.to_string())?;
+    let mut fetcher = Fetcher {
+        sink: sink.clone(),
+        id,
+        endpoint,
+        addr,
+        connection: None,
+        backoff: Backoff::new(options.retry),
+        writes: progress.writes(),
+        writes_at_failure: 0,
+    };
+    let tracker = Arc::new(std::sync::Mutex::new(ProgressTracker::new(id)));
+    let update = tracker
+        .lock()
+        .unwrap()
+        .update(ProgressPhase::Connecting, 0, 0, None);
+    if let Some(update) = update {
+        sink.download_progress(update);
+    }
+    let (hash_seq, sizes) = fetcher.hash_seq_and_sizes(&hash_and_format.hash).await?;
 
-            let connection = endpoint
-                .connect(addr, iroh_blobs::protocol::ALPN)
-                .await
-                .map_err(|e| e.to_string())?;
+    // the root and the metadata blob are enough to know the names, after
+    // that only the selected children are requested
+    let meta = hash_seq
+        .iter()
+        .next()
+        .ok_or_else(|| SwiftsendError::Verification("Collection has no metadata".to_string()))?;
+    for blob in [hash_and_format.hash, meta] {
+        let target = HashAndFormat::raw(blob);
+        fetcher
+            .fetch(&db, target, IgnoreProgressSender::default())
+            .await?;
+    }
+    let collection = Collection::load_db(&db, &hash_and_format.hash)
+        .await
+        .map_err(|e| SwiftsendError::Store(e.to_string()))?;
+    // sizes[0] is the size of the metadata blob, the files follow in order
+    let (selected, file_sizes): (Vec<_>, Vec<u64>) = collection
+        .into_iter()
+        .zip(sizes.iter().skip(1).copied())
+        .enumerate()
+        .filter(|(_index, ((name, _hash), _size))| {
+            options
+                .select
+                .as_ref()
+                .is_none_or(|selection| selection.matches(name))
+        })
+        .map(|(index, (entry, size))| ((index + 1, entry), size))
+        .unzip();
+    // indices into the hash sequence, after the metadata blob
+    let (children, collection): (Vec<usize>, Collection) = selected.into_iter().unzip();
+    if collection.is_empty() && options.select.is_some() {
+        return Err(SwiftsendError::EmptySelection);
+    }
+    let payload_size = file_sizes.iter().sum::<u64>();
+    tracing::info!(
+        "receive {id}: {} files, {payload_size} bytes",
+        collection.len()
+    );
 
-            let hash_and_format = HashAndFormat {
-                hash: received_ticket.hash(),
-                format: received_ticket.format(),
-            };
+    let mut names = BTreeMap::new();
+    for (name, hash) in collection.iter() {
+        names.entry(*hash).or_insert_with(|| name.clone());
+    }
+    let reporter = tokio::spawn(report_download_progress(
+        sink.clone(),
+        recv,
+        FetchTracker::new(names, payload_size),
+        tracker.clone(),
+    ));
 
-            let (send, recv) = async_channel::bounded(32);
-            let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
+    disk::check_receive_space(&iroh_data_dir, &receive_path, payload_size)?;
+    if options.select.is_none() {
+        fetcher.fetch(&db, hash_and_format, progress).await?;
+    } else {
+        fetcher
+            .fetch_children(&db, hash_and_format.hash, &hash_seq, &children, progress)
+            .await?;
+    }
+    // the reporter is done once the last progress sender is gone. Files that
+    // were already in the store from an earlier attempt are never reported.
+    if let Err(e) = reporter.await {
+        tracing::warn!("download progress reporter failed: {e}");
+    }
+    let update =
+        tracker
+            .lock()
+            .unwrap()
+            .update(ProgressPhase::Fetching, payload_size, payload_size, None);
+    if let Some(update) = update {
+        sink.download_progress(update);
+    }
 
-            let (_hash_seq, sizes) =
-                get_hash_seq_and_sizes(&connection, &hash_and_format.hash, 1024 * 1024 * 32)
-                    .await
-                    .map_err(|e| e.to_string())?;
-            let total_size = sizes.iter().sum::<u64>();
-            let total_files = sizes.len().saturating_sub(1);
-            let payload_size = sizes.iter().skip(1).sum::<u64>();
+    disk::check_export_space(&receive_path, payload_size)?;
+    let staging = get_staging_dir(&receive_path, &hash_and_format.hash);
+    let on_progress = Arc::new(move |progress: ExportProgress| {
+        let update = tracker.lock().unwrap().export(progress);
+        if let Some(update) = update {
+            sink.download_progress(update);
+        }
+    });
+    let staged = export::stage(
+        db,
+        collection,
+        &receive_path,
+        &staging,
+        options.conflict,
+        on_progress,
+    )
+    .await?;
 
-            println!("Total size: {}", total_size);
-            println!("Total files: {}", total_files);
+    Ok(Downloaded {
+        size: payload_size,
+        staged,
+    })
+}
 
-            tokio::spawn(send_download_progress(app, recv, total_size, total_files));
+/// Everything is fetched and staged and only has to be moved into place.
+struct Downloaded {
+    /// Payload size in bytes.
+    size: u64,
+    staged: StagedExport,
+}
 
-            let get_conn = || async move { Ok(connection) };
-            iroh_blobs::get::db::get_to_db(&db, get_conn, &hash_and_format, progress)
-                .await
-                .map_err(|e| e.to_string())?;
+impl Downloaded {
+    async fn commit(self, iroh_data_dir: &Path) -> Result<Received, SwiftsendError> {
+        let report = self.staged.commit().await?;
+        tokio::fs::remove_dir_all(iroh_data_dir).await?;
+        Ok(Received {
+            size: self.size,
+            report,
+        })
+    }
+}
 
-            let collection = Collection::load_db(&db, &hash_and_format.hash)
-                .await
-                .map_err(|e| e.to_string())?;
+/// Look at the files in `ticket` without downloading them.
+///
+/// Connects to the sender and fetches only the collection metadata, so the
+/// user can see names and sizes before deciding to receive.
+pub async fn inspect(ticket: &BlobTicket, network: Network) -> Result<Manifest, SwiftsendError> {
+    if ticket.format() != BlobFormat::HashSeq {
+        return Err(SwiftsendError::InvalidTicket(
+            "Ticket does not refer to a collection".to_string(),
+        ));
+    }
+    let endpoint = network.bind(vec![]).await?;
+    let connection = endpoint
+        .connect(ticket.node_addr().clone(), iroh_blobs::protocol::ALPN)
+        .await
+        .map_err(|e| SwiftsendError::Connection(e.to_string()))?;
+    let manifest = fetch_manifest(&connection, ticket).await?;
+    connection.close(0u32.into(), b"done");
+    Ok(manifest)
+}
 
-            export(db, collection, &receive_path)
-                .await
-                .map_err(|e| e.to_string())?;
-            tokio::fs::remove_dir_all(iroh_data_dir)
-                .await
-                .map_err(|e| e.to_string())?;
+/// The largest payload [`receive_text`] accepts.
+pub const MAX_TEXT_SIZE: u64 = 1024 * 1024;
 
-            Ok(())
-        })
+/// A text payload received into memory.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceivedText {
+    /// The name of the single entry of the collection.
+    pub name: String,
+    pub text: String,
+}
+
+/// Receive the text in `ticket` into memory instead of exporting it.
+///
+/// The collection must hold a single UTF-8 entry of at most
+/// [`MAX_TEXT_SIZE`] bytes, which is checked before anything but the
+/// metadata is fetched. Nothing is written to disk.
+pub async fn receive_text(
+    ticket: &BlobTicket,
+    network: Network,
+) -> Result<ReceivedText, SwiftsendError> {
+    if ticket.format() != BlobFormat::HashSeq {
+        return Err(SwiftsendError::InvalidTicket(
+            "Ticket does not refer to a collection".to_string(),
+        ));
+    }
+    let endpoint = network.bind(vec![]).await?;
+    let connection = endpoint
+        .connect(ticket.node_addr().clone(), iroh_blobs::protocol::ALPN)
+        .await
+        .map_err(|e| SwiftsendError::Connection(e.to_string()))?;
+    let manifest = fetch_manifest(&connection, ticket).await?;
+    let [entry] = manifest.files.as_slice() else {
+        return Err(SwiftsendError::InvalidArgument(format!(
+            "expected a single entry, the collection has {}",
+            manifest.files.len()
+        )));
+    };
+    if entry.size > MAX_TEXT_SIZE {
+        return Err(SwiftsendError::InvalidArgument(format!(
+            "{} is {} bytes, too large to receive as text",
+            entry.name, entry.size
+        )));
+    }
+
+    let db = iroh_blobs::store::mem::Store::new();
+    let target = HashAndFormat::hash_seq(ticket.hash());
+    let get_conn = || async { Ok(connection.clone()) };
+    // the root and the metadata blob first, like a receive, so that the
+    // sender counts the request for the data as a download
+    let raw = HashAndFormat::raw(target.hash);
+    iroh_blobs::get::db::get_to_db(&db, get_conn, &raw, IgnoreProgressSender::default()).await?;
+    let root = db
+        .get(&target.hash)
+        .await?
+        .ok_or_else(|| SwiftsendError::Store("collection root is missing".to_string()))?
+        .data_reader()
+        .await?
+        .read_to_end()
+        .await?;
+    let meta = HashSeq::try_from(root)?
+        .iter()
+        .next()
+        .ok_or_else(|| SwiftsendError::Verification("Collection has no metadata".to_string()))?;
+    let raw = HashAndFormat::raw(meta);
+    iroh_blobs::get::db::get_to_db(&db, get_conn, &raw, IgnoreProgressSender::default()).await?;
+    iroh_blobs::get::db::get_to_db(&db, get_conn, &target, IgnoreProgressSender::default()).await?;
+    connection.close(0u32.into(), b"done");
+
+    let collection = Collection::load_db(&db, &target.hash)
+        .await
+        .map_err(|e| SwiftsendError::Store(e.to_string()))?;
+    let (name, hash) = collection
+        .iter()
+        .next()
+        .ok_or_else(|| SwiftsendError::Verification("Collection is empty".to_string()))?;
+    let data = db
+        .get(hash)
+        .await?
+        .ok_or_else(|| SwiftsendError::Store(format!("blob for {name} is missing")))?
+        .data_reader()
+        .await?
+        .read_to_end()
+        .await?;
+    let text = String::from_utf8(data.into())
+        .map_err(|_| SwiftsendError::InvalidArgument(format!("{name} is not valid UTF-8 text")))?;
+    Ok(ReceivedText {
+        name: name.clone(),
+        text,
     })
-    .await
-    .map_err(|e| e.to_string())?
 }
@@ -1,6 +1,44 @@
+mod cli;
+mod commands;
+mod diagnostics;
+mod disk;
+mod error;
+mod export;
+mod history;
 mod iroh_send;
+mod logging;
+mod manifest;
+mod progress;
+mod retry;
+mod sanitize;
+mod throttle;
+mod transfers;
 
-use iroh_send::{receive_files, send_files, shutdown};
+use commands::{
+    cancel_receive, cancel_share, clear_history, discard_incomplete_download, inspect_ticket,
+    list_history, list_incomplete_downloads, receive_files, resume_receive, send_bytes, send_files,
+    send_text, set_download_limit, set_receive_limit, shutdown,
+};
+use diagnostics::export_diagnostics;
+
+// The transfers without the app, for embedding them in other programs.
+pub use error::SwiftsendError;
+pub use export::{ConflictPolicy, ExportAction, ExportReport, ExportedFile};
+pub use history::{
+    default_data_dir, HistoryEntry, IncompleteDownload, ReceiveRecord, SendRecord, TransferResult,
+};
+pub use iroh_send::{
+    default_store_dir, discard_incomplete, inspect, is_resumable, parse_ticket, receive,
+    receive_text, share, share_bytes, Network, ReceiveOptions, Received, ReceivedText, Share,
+    TransferContext, MAX_TEXT_SIZE,
+};
+pub use manifest::{Manifest, ManifestEntry, Selection};
+pub use progress::{
+    FileProgress, ImportProgressUpdate, ProgressPhase, ProgressSink, ProgressUpdate, Reconnecting,
+};
+pub use retry::RetryPolicy;
+pub use throttle::set_default_limit;
+pub use transfers::{new_transfer_id, CancelHandle, TransferId};
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
@@ -8,17 +46,53 @@ fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
+/// Entry point of the `swiftsend-cli` binary.
+pub fn run_cli() -> std::process::ExitCode {
+    cli::run()
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_os::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_opener::init())
+        .setup(|app| {
+            if let Err(e) = logging::init(app.handle()) {
+                eprintln!("failed to set up logging: {e:#}");
+            }
+            match commands::data_dir(app.handle()) {
+                Ok(dir) => {
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = history::close_open_sends(&dir).await {
+                            tracing::warn!("failed to close stale shares in history: {e}");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("failed to close stale shares in history: {e}"),
+            }
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             greet,
             send_files,
+            send_text,
+            send_bytes,
+            cancel_share,
             shutdown,
-            receive_files
+            receive_files,
+            // the core function of the same name is re-exported above
+            commands::receive_text,
+            inspect_ticket,
+            cancel_receive,
+            resume_receive,
+            list_incomplete_downloads,
+            discard_incomplete_download,
+            set_download_limit,
+            set_receive_limit,
+            list_history,
+            clear_history,
+            export_diagnostics
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,82 @@
+//! Log output of the app.
+//!
+//! Everything goes through `tracing`. Events are written to stderr for
+//! development and to a daily rotated file in the app log directory, which
+//! is what ends up in a diagnostics bundle.
+
+use std::{path::PathBuf, sync::OnceLock};
+
+use anyhow::Context;
+use tauri::{AppHandle, Manager};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Overrides the default filter, e.g. `SWIFTSEND_LOG=debug,iroh=info`.
+/// `RUST_LOG` is used if this is not set.
+const FILTER_ENV: &str = "SWIFTSEND_LOG";
+
+const DEFAULT_FILTER: &str = "info,iroh=warn,iroh_blobs=info";
+
+/// The CLI only logs warnings by default, anything more would fight with its
+/// progress bars.
+const TERMINAL_FILTER: &str = "warn";
+
+pub const LOG_FILE_PREFIX: &str = "swiftsend";
+
+/// Number of daily log files kept around.
+const MAX_LOG_FILES: usize = 7;
+
+/// Keeps the background writer of the log file alive and flushes it on exit.
+static GUARD: OnceLock<WorkerGuard> = OnceLock::new();
+
+pub fn log_dir(app: &AppHandle) -> anyhow::Result<PathBuf> {
+    app.path()
+        .app_log_dir()
+        .context("failed to resolve app log dir")
+}
+
+fn filter(default: &str) -> EnvFilter {
+    EnvFilter::try_from_env(FILTER_ENV)
+        .or_else(|_| EnvFilter::try_from_default_env())
+        .unwrap_or_else(|_| EnvFilter::new(default))
+}
+
+/// Install the global subscriber. Called once from the app setup.
+pub fn init(app: &AppHandle) -> anyhow::Result<()> {
+    let filter = filter(DEFAULT_FILTER);
+
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(log_dir(app)?)
+        .context("failed to open log file")?;
+    let (file_writer, guard) = tracing_appender::non_blocking(appender);
+    let _ = GUARD.set(guard);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(fmt::layer().with_writer(file_writer).with_ansi(false))
+        .try_init()?;
+    Ok(())
+}
+
+/// Install a subscriber that only writes to stderr, for the CLI.
+/// `verbose` lowers the default filter to that of the app.
+pub fn init_terminal(verbose: bool) -> anyhow::Result<()> {
+    let filter = filter(if verbose {
+        DEFAULT_FILTER
+    } else {
+        TERMINAL_FILTER
+    });
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .try_init()?;
+    Ok(())
+}
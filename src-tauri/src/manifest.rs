@@ -0,0 +1,167 @@
+//! Looking at the contents of a collection without downloading it.
+
+use bao_tree::ChunkRanges;
+use iroh::endpoint::Connection;
+use iroh_blobs::{
+    format::collection::Collection,
+    get::{
+        fsm::{self, ConnectedNext, EndBlobNext},
+        request::get_hash_seq_and_sizes,
+    },
+    protocol::{GetRequest, RangeSpecSeq},
+    ticket::BlobTicket,
+};
+use serde::Serialize;
+
+/// Upper bound for the size of the hash sequence we are willing to fetch,
+/// 32 MiB are enough for a collection of about a million files.
+pub const MAX_HASH_SEQ_SIZE: u64 = 1024 * 1024 * 32;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+/// The file names and sizes of a collection.
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    /// The node id of the sender.
+    pub node_id: String,
+    /// Hex encoded hash of the collection.
+    pub hash: String,
+    pub files: Vec<ManifestEntry>,
+    /// Size of all files in bytes.
+    pub total_size: u64,
+}
+
+/// Picks entries of a collection by exact name, directory prefix or glob pattern.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    names: Vec<String>,
+    patterns: Vec<glob::Pattern>,
+}
+
+/// How selection patterns match names. A `*` or `?` stays within one path
+/// component, so `docs/*.md` does not pick up `docs/api/index.md`.
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl Selection {
+    /// Every name selects the entry or directory it names. Names with glob
+    /// meta characters are also matched as patterns, unless they are not
+    /// valid patterns, like `a[b.txt`.
+    pub fn new(patterns: &[String]) -> Self {
+        let names = patterns
+            .iter()
+            .map(|name| name.trim_end_matches('/').to_string())
+            .collect();
+        let patterns = patterns
+            .iter()
+            .filter(|pattern| pattern.contains(['*', '?', '[']))
+            .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+            .collect();
+        Self { names, patterns }
+    }
+
+    /// Whether the entry `name` is selected. Naming a directory selects
+    /// everything below it.
+    pub fn matches(&self, name: &str) -> bool {
+        self.names.iter().any(|selected| {
+            name == selected
+                || name
+                    .strip_prefix(selected.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }) || self
+            .patterns
+            .iter()
+            .any(|pattern| pattern.matches_with(name, MATCH_OPTIONS))
+    }
+}
+
+/// Fetch the manifest of the collection in `ticket` over `connection`.
+///
+/// Only the hash sequence, the collection metadata blob and the last chunk
+/// of every file (to verify its size) are transferred.
+pub async fn fetch_manifest(
+    connection: &Connection,
+    ticket: &BlobTicket,
+) -> anyhow::Result<Manifest> {
+    let hash = ticket.hash();
+    let (_hash_seq, sizes) = get_hash_seq_and_sizes(connection, &hash, MAX_HASH_SEQ_SIZE).await?;
+
+    // the root hash sequence and its first child, the metadata blob with the names
+    let ranges = RangeSpecSeq::from_ranges([ChunkRanges::all(), ChunkRanges::all()]);
+    let request = GetRequest::new(hash, ranges);
+    let connected = fsm::start(connection.clone(), request).next().await?;
+    let ConnectedNext::StartRoot(start_root) = connected.next().await? else {
+        anyhow::bail!("expected the collection root");
+    };
+    let (next, _hash_seq, collection) = Collection::read_fsm(start_root).await?;
+    if let EndBlobNext::Closing(closing) = next {
+        closing.next().await?;
+    }
+
+    // sizes[0] is the size of the metadata blob, the files follow in order
+    let files = collection
+        .iter()
+        .zip(sizes.iter().skip(1))
+        .map(|((name, _hash), size)| ManifestEntry {
+            name: name.clone(),
+            size: *size,
+        })
+        .collect::<Vec<_>>();
+    anyhow::ensure!(
+        files.len() == collection.len(),
+        "collection has {} entries but the hash sequence only {}",
+        collection.len(),
+        sizes.len().saturating_sub(1)
+    );
+    let total_size = files.iter().map(|file| file.size).sum();
+    Ok(Manifest {
+        node_id: ticket.node_addr().node_id.to_string(),
+        hash: hash.to_hex(),
+        files,
+        total_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(patterns: &[&str]) -> Selection {
+        Selection::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn selects_names_and_directories() {
+        let select = selection(&["docs/", "a[b.txt", "notes.txt"]);
+        assert!(select.matches("docs/guide.md"));
+        assert!(select.matches("docs/api/index.md"));
+        assert!(select.matches("a[b.txt"));
+        assert!(select.matches("notes.txt"));
+        assert!(!select.matches("docs.md"));
+        assert!(!select.matches("other/notes.txt"));
+    }
+
+    #[test]
+    fn patterns_stay_within_a_component() {
+        let select = selection(&["docs/*.md", "*.txt"]);
+        assert!(select.matches("docs/guide.md"));
+        assert!(!select.matches("docs/api/index.md"));
+        assert!(select.matches("notes.txt"));
+        assert!(!select.matches("docs/notes.txt"));
+    }
+
+    #[test]
+    fn names_with_meta_characters_match_literally_too() {
+        let select = selection(&["[ab].txt"]);
+        assert!(select.matches("a.txt"));
+        assert!(select.matches("[ab].txt"));
+        assert!(!select.matches("c.txt"));
+    }
+}
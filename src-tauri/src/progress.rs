@@ -0,0 +1,576 @@
+//! Progress of imports and receives as shown to the user.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use iroh_blobs::{get::db::DownloadProgress, store::ImportProgress, Hash};
+use serde::Serialize;
+
+use crate::{export::ExportProgress, transfers::TransferId};
+
+/// How often the throughput estimate takes a new sample.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Weight of the newest sample in the smoothed throughput.
+const SMOOTHING: f64 = 0.3;
+
+/// Shortest time between two updates of the same transfer. A tree with
+/// hundreds of thousands of small files would flood the webview otherwise.
+const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressPhase {
+    /// Connecting to the sender and fetching the list of files.
+    Connecting,
+    /// Fetching the data from the sender into the local store.
+    Fetching,
+    /// Writing the files from the store into the destination.
+    Exporting,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileProgress {
+    pub name: String,
+    pub progress: u64,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressUpdate {
+    pub id: TransferId,
+    pub phase: ProgressPhase,
+    /// Bytes done over all files in this phase.
+    pub progress: u64,
+    pub total: u64,
+    /// The file currently being fetched or written.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileProgress>,
+    /// Smoothed throughput in bytes per second.
+    pub rate: u64,
+    /// Estimated seconds until the phase is done, once there is a rate to go by.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+}
+
+/// The sender dropped and a receive is waiting to reconnect.
+#[derive(Debug, Clone, Serialize)]
+pub struct Reconnecting {
+    pub id: TransferId,
+    pub attempt: u32,
+    pub delay_ms: u64,
+    pub error: String,
+}
+
+/// Where transfers report their progress.
+///
+/// The app forwards everything to the frontend as events, the CLI draws
+/// progress bars in the terminal.
+pub trait ProgressSink: Send + Sync + 'static {
+    fn import_progress(&self, update: ImportProgressUpdate);
+
+    fn download_progress(&self, update: ProgressUpdate);
+
+    fn reconnecting(&self, event: Reconnecting);
+}
+
+/// Drops updates that come in faster than [`MIN_UPDATE_INTERVAL`].
+#[derive(Debug, Default)]
+struct Coalesce {
+    last: Option<Instant>,
+}
+
+impl Coalesce {
+    /// Whether an update should go out now. `force` is for updates that must
+    /// not get lost, like the last one of a phase.
+    fn ready(&mut self, force: bool) -> bool {
+        let due = self
+            .last
+            .is_none_or(|last| last.elapsed() >= MIN_UPDATE_INTERVAL);
+        if due || force {
+            self.last = Some(Instant::now());
+        }
+        due || force
+    }
+}
+
+/// Exponentially smoothed throughput over a growing byte count.
+#[derive(Debug)]
+struct Throughput {
+    sampled_at: Instant,
+    sampled: u64,
+    rate: Option<f64>,
+}
+
+impl Throughput {
+    fn new(offset: u64) -> Self {
+        Self {
+            sampled_at: Instant::now(),
+            sampled: offset,
+            rate: None,
+        }
+    }
+
+    fn update(&mut self, offset: u64) -> Option<f64> {
+        let elapsed = self.sampled_at.elapsed();
+        if elapsed >= SAMPLE_INTERVAL {
+            let current = offset.saturating_sub(self.sampled) as f64 / elapsed.as_secs_f64();
+            self.rate = Some(match self.rate {
+                Some(rate) => SMOOTHING * current + (1.0 - SMOOTHING) * rate,
+                None => current,
+            });
+            self.sampled_at = Instant::now();
+            self.sampled = offset;
+        }
+        self.rate
+    }
+}
+
+/// Turns raw byte counts of a receive into [`ProgressUpdate`]s.
+///
+/// The throughput starts over whenever the phase changes, since fetching
+/// and exporting run at unrelated speeds. Updates are coalesced, but the
+/// first one of a phase and the one that completes it always go out. A phase
+/// completes only once, later updates that complete it again are dropped.
+#[derive(Debug)]
+pub struct ProgressTracker {
+    id: TransferId,
+    phase: ProgressPhase,
+    throughput: Throughput,
+    coalesce: Coalesce,
+    completed: bool,
+}
+
+impl ProgressTracker {
+    pub fn new(id: TransferId) -> Self {
+        Self {
+            id,
+            phase: ProgressPhase::Connecting,
+            throughput: Throughput::new(0),
+            coalesce: Coalesce::default(),
+            completed: false,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        phase: ProgressPhase,
+        progress: u64,
+        total: u64,
+        file: Option<FileProgress>,
+    ) -> Option<ProgressUpdate> {
+        let phase_changed = phase != self.phase;
+        if phase_changed {
+            self.phase = phase;
+            self.throughput = Throughput::new(progress);
+            self.completed = false;
+        }
+        let complete = progress >= total;
+        if complete && self.completed {
+            return None;
+        }
+        let rate = self.throughput.update(progress);
+        if !self.coalesce.ready(phase_changed || complete) {
+            return None;
+        }
+        self.completed = complete;
+        let eta_secs = rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| (total.saturating_sub(progress) as f64 / rate).ceil() as u64);
+        Some(ProgressUpdate {
+            id: self.id,
+            phase,
+            progress,
+            total,
+            file,
+            rate: rate.unwrap_or_default() as u64,
+            eta_secs,
+        })
+    }
+
+    pub fn export(&mut self, progress: ExportProgress) -> Option<ProgressUpdate> {
+        let file = FileProgress {
+            name: progress.name,
+            progress: progress.file_offset,
+            total: progress.file_size,
+        };
+        self.update(
+            ProgressPhase::Exporting,
+            progress.offset,
+            progress.total,
+            Some(file),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct FetchingBlob {
+    name: String,
+    size: u64,
+    offset: u64,
+}
+
+/// Sums up the per blob events of a fetch over all files.
+///
+/// `names` resolves the hashes of the files being fetched to their names in
+/// the collection. Blobs without a name, the collection root and its
+/// metadata, are not part of the payload and don't count.
+#[derive(Debug)]
+pub struct FetchTracker {
+    names: BTreeMap<Hash, String>,
+    total: u64,
+    /// The blobs being fetched, keyed by their progress id.
+    blobs: BTreeMap<u64, FetchingBlob>,
+    /// Bytes of the blobs that are done.
+    done: u64,
+}
+
+impl FetchTracker {
+    pub fn new(names: BTreeMap<Hash, String>, total: u64) -> Self {
+        Self {
+            names,
+            total,
+            blobs: BTreeMap::new(),
+            done: 0,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The bytes fetched over all files and the file `event` was about, or
+    /// `None` if it was not about a file of the payload.
+    pub fn update(&mut self, event: DownloadProgress) -> Option<(u64, FileProgress)> {
+        let file = match event {
+            DownloadProgress::Found { id, hash, size, .. } => {
+                let name = self.names.get(&hash)?.clone();
+                let blob = FetchingBlob {
+                    name: name.clone(),
+                    size,
+                    offset: 0,
+                };
+                self.blobs.insert(id, blob);
+                FileProgress {
+                    name,
+                    progress: 0,
+                    total: size,
+                }
+            }
+            DownloadProgress::Progress { id, offset } => {
+                let blob = self.blobs.get_mut(&id)?;
+                blob.offset = offset.min(blob.size);
+                FileProgress {
+                    name: blob.name.clone(),
+                    progress: blob.offset,
+                    total: blob.size,
+                }
+            }
+            DownloadProgress::Done { id } => {
+                let blob = self.blobs.remove(&id)?;
+                self.done += blob.size;
+                FileProgress {
+                    name: blob.name,
+                    progress: blob.size,
+                    total: blob.size,
+                }
+            }
+            DownloadProgress::Abort(e) => {
+                tracing::warn!("download aborted: {e:?}");
+                return None;
+            }
+            _ => return None,
+        };
+        let progress = self.done + self.blobs.values().map(|blob| blob.offset).sum::<u64>();
+        Some((progress.min(self.total), file))
+    }
+}
+
+/// Progress of importing the files of a share into the store.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgressUpdate {
+    pub id: TransferId,
+    /// Files found so far.
+    pub files_found: u64,
+    pub files_hashed: u64,
+    /// Bytes hashed over all files.
+    pub progress: u64,
+    /// Size of all files found so far.
+    pub total: u64,
+    /// The file the event was about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileProgress>,
+    /// Smoothed hashing throughput in bytes per second.
+    pub rate: u64,
+}
+
+#[derive(Debug)]
+struct ImportingFile {
+    name: String,
+    size: u64,
+    offset: u64,
+}
+
+/// Turns the per file events of an import into [`ImportProgressUpdate`]s.
+///
+/// Several files are hashed at once, and every file reports its own offset,
+/// so the bytes hashed are the sum of the finished files and the current
+/// offsets of the ones in flight.
+#[derive(Debug)]
+pub struct ImportTracker {
+    id: TransferId,
+    files: BTreeMap<u64, ImportingFile>,
+    files_found: u64,
+    files_hashed: u64,
+    /// Bytes of the files that are done.
+    hashed: u64,
+    total: u64,
+    throughput: Throughput,
+    coalesce: Coalesce,
+    finished: bool,
+}
+
+impl ImportTracker {
+    pub fn new(id: TransferId) -> Self {
+        Self {
+            id,
+            files: BTreeMap::new(),
+            files_found: 0,
+            files_hashed: 0,
+            hashed: 0,
+            total: 0,
+            throughput: Throughput::new(0),
+            coalesce: Coalesce::default(),
+            finished: false,
+        }
+    }
+
+    pub fn update(&mut self, event: ImportProgress) -> Option<ImportProgressUpdate> {
+        if self.finished {
+            return None;
+        }
+        let file = match event {
+            ImportProgress::Found { id, name } => {
+                self.files_found += 1;
+                let file = ImportingFile {
+                    name,
+                    size: 0,
+                    offset: 0,
+                };
+                self.files.entry(id).or_insert(file)
+            }
+            ImportProgress::Size { id, size } => {
+                let file = self.files.get_mut(&id)?;
+                self.total += size;
+                file.size = size;
+                file
+            }
+            ImportProgress::OutboardProgress { id, offset } => {
+                let file = self.files.get_mut(&id)?;
+                file.offset = offset.min(file.size);
+                file
+            }
+            ImportProgress::OutboardDone { id, .. } => {
+                // there is not necessarily any OutboardProgress before this
+                let file = self.files.remove(&id)?;
+                self.files_hashed += 1;
+                self.hashed += file.size;
+                let file = FileProgress {
+                    name: file.name,
+                    progress: file.size,
+                    total: file.size,
+                };
+                return self.snapshot(Some(file), false);
+            }
+            // we are not copying anything
+            ImportProgress::CopyProgress { .. } => return None,
+        };
+        let file = FileProgress {
+            name: file.name.clone(),
+            progress: file.offset,
+            total: file.size,
+        };
+        self.snapshot(Some(file), false)
+    }
+
+    /// The last update of the import, which is never coalesced away. Only
+    /// the first call has an update, anything after it is ignored.
+    pub fn finish(&mut self) -> Option<ImportProgressUpdate> {
+        if std::mem::replace(&mut self.finished, true) {
+            return None;
+        }
+        self.snapshot(None, true)
+    }
+
+    /// Bytes hashed over all files.
+    fn progress(&self) -> u64 {
+        self.hashed + self.files.values().map(|file| file.offset).sum::<u64>()
+    }
+
+    fn snapshot(
+        &mut self,
+        file: Option<FileProgress>,
+        force: bool,
+    ) -> Option<ImportProgressUpdate> {
+        let progress = self.progress();
+        let rate = self.throughput.update(progress);
+        if !self.coalesce.ready(force) {
+            return None;
+        }
+        Some(ImportProgressUpdate {
+            id: self.id,
+            files_found: self.files_found,
+            files_hashed: self.files_hashed,
+            progress,
+            total: self.total,
+            file,
+            rate: rate.unwrap_or_default() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_blobs::get::db::BlobId;
+
+    use super::*;
+
+    fn found(id: u64, name: &str) -> ImportProgress {
+        ImportProgress::Found {
+            id,
+            name: name.to_string(),
+        }
+    }
+
+    fn done(id: u64) -> ImportProgress {
+        ImportProgress::OutboardDone {
+            id,
+            hash: Hash::new(id.to_le_bytes()),
+        }
+    }
+
+    #[test]
+    fn import_progress_never_overshoots() {
+        use ImportProgress::{OutboardProgress, Size};
+
+        let mut tracker = ImportTracker::new(1);
+        let events = [
+            found(1, "a"),
+            found(2, "b"),
+            Size { id: 1, size: 100 },
+            OutboardProgress { id: 1, offset: 64 },
+            found(3, "c"),
+            Size { id: 2, size: 50 },
+            Size { id: 3, size: 10 },
+            // offsets are absolute and can be reported past the end
+            OutboardProgress { id: 2, offset: 80 },
+            OutboardProgress { id: 1, offset: 64 },
+            done(2),
+            // a file can be done without any progress before
+            done(3),
+            OutboardProgress { id: 1, offset: 100 },
+            done(1),
+            // events for unknown or finished files are ignored
+            OutboardProgress { id: 1, offset: 100 },
+            done(1),
+        ];
+        let mut last = 0;
+        for event in events {
+            let update = tracker.update(event);
+            let progress = tracker.progress();
+            assert!(progress >= last, "{progress} went back from {last}");
+            assert!(progress <= tracker.total, "{progress} > {}", tracker.total);
+            if let Some(update) = update {
+                assert_eq!(update.progress, progress);
+                assert!(update.files_hashed <= update.files_found);
+            }
+            last = progress;
+        }
+
+        let update = tracker.finish().expect("the last update");
+        assert_eq!((update.progress, update.total), (160, 160));
+        assert_eq!((update.files_found, update.files_hashed), (3, 3));
+        assert!(tracker.finish().is_none());
+        assert!(tracker.update(found(4, "d")).is_none());
+    }
+
+    #[test]
+    fn throughput_is_smoothed() {
+        let mut throughput = Throughput::new(0);
+        // too early for a sample
+        assert_eq!(throughput.update(1_000), None);
+
+        throughput.sampled_at = Instant::now() - Duration::from_secs(1);
+        let rate = throughput.update(1_000).unwrap();
+        assert!((950.0..=1_000.0).contains(&rate), "{rate}");
+
+        throughput.sampled_at = Instant::now() - Duration::from_secs(1);
+        let rate = throughput.update(4_000).unwrap();
+        // 0.3 of the new 3000 B/s and 0.7 of the old rate
+        assert!((1_550.0..=1_600.0).contains(&rate), "{rate}");
+        assert_eq!(throughput.sampled, 4_000);
+    }
+
+    #[test]
+    fn fetch_progress_never_overshoots_and_completes_once() {
+        let file = |n: u8| Hash::new([n]);
+        let names = BTreeMap::from([(file(1), "a".to_string()), (file(2), "b".to_string())]);
+        let found = |id, hash, size| DownloadProgress::Found {
+            id,
+            child: BlobId::Root,
+            hash,
+            size,
+        };
+        let at = |id, offset| DownloadProgress::Progress { id, offset };
+        let done = |id| DownloadProgress::Done { id };
+        let events = [
+            // the metadata blob is not part of the payload
+            found(1, Hash::new(b"meta"), 500),
+            at(1, 500),
+            done(1),
+            found(2, file(1), 100),
+            // a resumed blob reports what it already had right away
+            found(3, file(2), 50),
+            at(3, 40),
+            at(2, 64),
+            at(3, 50),
+            done(3),
+            at(2, 100),
+            // a write past the end of the blob
+            at(2, 120),
+            done(2),
+            // events for finished blobs are ignored
+            at(2, 100),
+            done(2),
+        ];
+
+        let mut fetch = FetchTracker::new(names, 150);
+        let mut tracker = ProgressTracker::new(1);
+        let mut updates = Vec::new();
+        updates.extend(tracker.update(ProgressPhase::Connecting, 0, 0, None));
+        let mut last = 0;
+        for event in events {
+            let Some((progress, file)) = fetch.update(event) else {
+                continue;
+            };
+            assert!(progress >= last, "{progress} went back from {last}");
+            assert!(progress <= 150, "{progress}");
+            assert!(file.progress <= file.total);
+            last = progress;
+            updates.extend(tracker.update(ProgressPhase::Fetching, progress, 150, Some(file)));
+        }
+        assert_eq!(last, 150);
+        // the receive reports the end of the fetch once more
+        updates.extend(tracker.update(ProgressPhase::Fetching, 150, 150, None));
+
+        let complete = updates
+            .iter()
+            .filter(|update| update.phase == ProgressPhase::Fetching)
+            .filter(|update| update.progress >= update.total)
+            .count();
+        assert_eq!(complete, 1);
+        assert!(updates.iter().all(|update| update.progress <= update.total));
+    }
+}
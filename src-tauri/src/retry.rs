@@ -0,0 +1,206 @@
+//! Reconnect policy for receives.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use iroh::endpoint::ConnectionError;
+use iroh_blobs::get::{
+    error::GetError,
+    fsm::{AtBlobHeaderNextError, ConnectedNextError, DecodeError},
+};
+use rand::Rng;
+use serde::Deserialize;
+
+/// How often and for how long a receive tries to reconnect after the
+/// connection to the sender dropped.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of reconnect attempts after the first failure of an outage.
+    pub max_retries: u32,
+    /// Stop retrying once this many seconds have passed since the first
+    /// failure of an outage. `None` retries until `max_retries` is used up.
+    pub deadline_secs: Option<u64>,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            deadline_secs: Some(10 * 60),
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+/// Exponential backoff with jitter, driven by a [`RetryPolicy`].
+///
+/// The policy applies to one outage at a time: [`Backoff::reset`] starts over
+/// once the transfer makes progress again.
+#[derive(Debug)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    attempt: u32,
+    /// When the first failure of the current outage was handled.
+    outage_started: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            attempt: 0,
+            outage_started: None,
+        }
+    }
+
+    /// Forget the current outage, the next failure starts a new one.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.outage_started = None;
+    }
+
+    /// The number of the reconnect attempt that was last handed out.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// How long to wait before the next attempt, or `None` if the policy is exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempt >= self.policy.max_retries {
+            return None;
+        }
+        let base = self
+            .policy
+            .initial_backoff_ms
+            .saturating_mul(1u64 << self.attempt.min(16))
+            .min(self.policy.max_backoff_ms);
+        // pick a delay in [base / 2, base] so that receivers of the same
+        // sender don't all come back at the same instant
+        let delay = Duration::from_millis(rand::thread_rng().gen_range(base / 2..=base));
+        let outage_started = *self.outage_started.get_or_insert_with(Instant::now);
+        if let Some(deadline) = self.policy.deadline_secs {
+            if outage_started.elapsed() + delay > Duration::from_secs(deadline) {
+                return None;
+            }
+        }
+        self.attempt += 1;
+        Some(delay)
+    }
+}
+
+/// Whether a failed `get_to_db` is worth another attempt on a fresh connection.
+///
+/// Only transport failures are retried. A sender that does not have the data
+/// or sends data that does not verify will not do better the second time.
+pub fn is_retryable(error: &GetError) -> bool {
+    matches!(error, GetError::Io(_) | GetError::RemoteReset(_))
+}
+
+/// The [`GetError`] behind a failure of the request helpers of iroh-blobs,
+/// which hand out the error of the step that failed as an `anyhow::Error`.
+///
+/// Anything else is a response that didn't make sense, which is not worth
+/// retrying either.
+pub fn get_error(error: anyhow::Error) -> GetError {
+    let error = match error.downcast::<GetError>() {
+        Ok(e) => return e,
+        Err(e) => e,
+    };
+    let error = match error.downcast::<AtBlobHeaderNextError>() {
+        Ok(e) => return e.into(),
+        Err(e) => e,
+    };
+    let error = match error.downcast::<DecodeError>() {
+        Ok(e) => return e.into(),
+        Err(e) => e,
+    };
+    let error = match error.downcast::<ConnectedNextError>() {
+        Ok(e) => return e.into(),
+        Err(e) => e,
+    };
+    let error = match error.downcast::<ConnectionError>() {
+        Ok(e) => return e.into(),
+        Err(e) => e,
+    };
+    match error.downcast::<io::Error>() {
+        Ok(e) => GetError::Io(e.into()),
+        Err(e) => GetError::NoncompliantNode(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_retries: u32, deadline_secs: Option<u64>) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            deadline_secs,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+        }
+    }
+
+    fn ms(delay: Option<Duration>) -> u64 {
+        delay.expect("another attempt").as_millis() as u64
+    }
+
+    #[test]
+    fn grows_exponentially_up_to_the_cap() {
+        let mut backoff = Backoff::new(policy(8, None));
+        for base in [100, 200, 400, 800, 1_000, 1_000, 1_000, 1_000] {
+            let delay = ms(backoff.next_delay());
+            assert!((base / 2..=base).contains(&delay), "{delay} for {base}");
+        }
+        assert_eq!(backoff.attempt(), 8);
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn stops_at_the_deadline() {
+        let mut backoff = Backoff::new(policy(10, Some(0)));
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempt(), 0);
+
+        // a delay that would end after the deadline is not handed out
+        let mut backoff = Backoff::new(RetryPolicy {
+            initial_backoff_ms: 4_000,
+            max_backoff_ms: 4_000,
+            ..policy(10, Some(1))
+        });
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn reset_starts_a_new_outage() {
+        let mut backoff = Backoff::new(policy(2, Some(60)));
+        ms(backoff.next_delay());
+        ms(backoff.next_delay());
+        assert_eq!(backoff.next_delay(), None);
+        backoff.reset();
+        assert!(ms(backoff.next_delay()) <= 100);
+
+        // an outage that started long ago has no time left, a new one has
+        backoff.outage_started = Instant::now().checked_sub(Duration::from_secs(3600));
+        assert_eq!(backoff.next_delay(), None);
+        backoff.reset();
+        assert!(ms(backoff.next_delay()) <= 100);
+        assert_eq!(backoff.attempt(), 1);
+    }
+
+    #[test]
+    fn retries_transport_failures_only() {
+        let reset = anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(is_retryable(&get_error(reset)));
+        let not_found = anyhow::Error::new(AtBlobHeaderNextError::NotFound);
+        assert!(!is_retryable(&get_error(not_found)));
+        let nonsense = anyhow::anyhow!("hash sequence too large");
+        assert!(!is_retryable(&get_error(nonsense)));
+    }
+}
@@ -0,0 +1,529 @@
+//! Mapping collection names to names the local filesystem can store.
+//!
+//! Collections built on Linux may contain names that Windows rejects, such
+//! as `aux.txt`, `a:b` or `name.`. Characters Windows can't store are mapped
+//! to the private use area at `U+F000 + c`, the same scheme Cygwin and WSL
+//! use, so the result is deterministic and [`restore_component`] turns it
+//! back into the original name. macOS and Linux accept every name that
+//! passes validation, so there only the length limit applies.
+//!
+//! Names that are too long are shortened and tagged with a hash of the
+//! original. That can't be undone from the name alone, but the export
+//! report lists the original name next to every sanitized path.
+//!
+//! Names are stored in a collection in Unicode normalization form C, and
+//! [`collision_key`] tells which names a case-insensitive filesystem would
+//! treat as the same file.
+//!
+//! Names that are not valid UTF-8 can't be stored in a collection as they
+//! are. [`encode_component`] maps every byte that isn't part of a valid
+//! character to `U+F000 + byte`, next to the range used for Windows, and
+//! [`decode_component`] turns them back into raw bytes where the filesystem
+//! allows it. Every shared name is encoded, valid UTF-8 or not, so a name
+//! that already contains characters from that range survives the trip.
+
+use std::{
+    borrow::Cow,
+    collections::{btree_map::Entry, BTreeMap},
+    ffi::{OsStr, OsString},
+};
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest file name, in bytes, that every supported filesystem can store.
+pub const MAX_NAME_BYTES: usize = 255;
+
+const ESCAPE_BASE: u32 = 0xF000;
+
+/// Characters that are not allowed anywhere in a Windows file name.
+const WINDOWS_RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows reserves, with or without an extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Windows,
+    Unix,
+}
+
+impl Platform {
+    pub const CURRENT: Platform = if cfg!(windows) {
+        Platform::Windows
+    } else {
+        Platform::Unix
+    };
+}
+
+fn escape(c: char) -> char {
+    debug_assert!(c.is_ascii());
+    char::from_u32(ESCAPE_BASE + c as u32).expect("private use area is valid")
+}
+
+fn unescape(c: char) -> Option<char> {
+    let value = (c as u32).checked_sub(ESCAPE_BASE)?;
+    (value < 0x80).then(|| char::from(value as u8))
+}
+
+/// Whether `c` stands for a raw byte of a name that isn't valid UTF-8.
+fn is_escaped_byte(c: char) -> bool {
+    (ESCAPE_BASE + 0x80..ESCAPE_BASE + 0x100).contains(&(c as u32))
+}
+
+fn escape_byte(b: u8) -> char {
+    char::from_u32(ESCAPE_BASE + u32::from(b)).expect("private use area is valid")
+}
+
+fn is_reserved_device(stem: &str) -> bool {
+    WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+fn sanitize_windows(component: &str) -> Cow<'_, str> {
+    let mut chars = component.chars().collect::<Vec<_>>();
+    let mut changed = false;
+    for c in chars.iter_mut() {
+        if WINDOWS_RESERVED_CHARS.contains(c) {
+            *c = escape(*c);
+            changed = true;
+        }
+    }
+    // windows silently strips trailing dots and spaces
+    for c in chars.iter_mut().rev() {
+        if *c != '.' && *c != ' ' {
+            break;
+        }
+        *c = escape(*c);
+        changed = true;
+    }
+    // `aux`, `AUX.txt` and `aux .tar.gz` all refer to the device
+    let stem_end = chars.iter().position(|c| *c == '.').unwrap_or(chars.len());
+    let stem = chars[..stem_end].iter().collect::<String>();
+    let stem = stem.trim_end_matches(' ');
+    if is_reserved_device(stem) {
+        let last = stem.chars().count() - 1;
+        chars[last] = escape(chars[last]);
+        changed = true;
+    }
+    if changed {
+        Cow::Owned(chars.into_iter().collect())
+    } else {
+        Cow::Borrowed(component)
+    }
+}
+
+/// The bytes `c` takes up in the name [`decode_component`] stores. An escaped
+/// byte is a single byte where raw names are stored, and otherwise at most
+/// as long as its `%XX` in the readable form.
+fn stored_len(c: char, raw_names: bool) -> usize {
+    if raw_names && is_escaped_byte(c) {
+        1
+    } else {
+        c.len_utf8()
+    }
+}
+
+/// Shorten `name` to [`MAX_NAME_BYTES`] once stored, keeping a short
+/// extension and tagging it with a hash of `original` so different long
+/// names stay apart.
+fn shorten(original: &str, name: &str, raw_names: bool) -> String {
+    let tag = &blake3::hash(original.as_bytes()).to_hex()[..8];
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= 16 => (stem, Some(ext)),
+        _ => (name, None),
+    };
+    let suffix = match ext {
+        Some(ext) => format!("~{tag}.{ext}"),
+        None => format!("~{tag}"),
+    };
+    let budget = MAX_NAME_BYTES
+        - suffix
+            .chars()
+            .map(|c| stored_len(c, raw_names))
+            .sum::<usize>();
+    let mut used = 0;
+    let end = stem
+        .char_indices()
+        .find(|(_, c)| {
+            used += stored_len(*c, raw_names);
+            used > budget
+        })
+        .map_or(stem.len(), |(end, _)| end);
+    format!("{}{suffix}", &stem[..end])
+}
+
+/// Map a single, already validated path component to a name `platform` can store.
+///
+/// The length limit applies to the name as it is stored, after
+/// [`decode_component`], not to its encoded form.
+pub fn sanitize_component(component: &str, platform: Platform) -> Cow<'_, str> {
+    let name = match platform {
+        Platform::Windows => sanitize_windows(component),
+        Platform::Unix => Cow::Borrowed(component),
+    };
+    let raw_names = platform == Platform::Unix && SUPPORTS_RAW_NAMES;
+    let stored = name
+        .chars()
+        .map(|c| stored_len(c, raw_names))
+        .sum::<usize>();
+    if stored > MAX_NAME_BYTES {
+        return Cow::Owned(shorten(component, &name, raw_names));
+    }
+    name
+}
+
+/// Whether `sanitize_windows` can map `c` into the private use area: a
+/// reserved character, a trailing dot or space, or the last letter of a
+/// device name.
+fn is_mapped_by_windows(c: char) -> bool {
+    WINDOWS_RESERVED_CHARS.contains(&c)
+        || matches!(c, '.' | ' ' | 'N' | 'n' | 'X' | 'x' | 'L' | 'l' | '1'..='9')
+}
+
+/// Undo the character mapping of [`sanitize_component`].
+///
+/// Used when sharing files that were received with sanitized names, so they
+/// go out under their original names again. Only names the mapping could have
+/// produced are restored, anything else from the private use area is kept as
+/// it is.
+pub fn restore_component(component: &str) -> Cow<'_, str> {
+    let restore = |c: char| unescape(c).filter(|c| is_mapped_by_windows(*c));
+    if !component.chars().any(|c| restore(c).is_some()) {
+        return Cow::Borrowed(component);
+    }
+    let restored = component
+        .chars()
+        .map(|c| restore(c).unwrap_or(c))
+        .collect::<String>();
+    // e.g. an escaped dot in the middle of a name is not something the
+    // mapping produces, so the name did not come from it. Nor does it come
+    // from `.` or `..`, which are never exported.
+    if sanitize_windows(&restored) != component || restored == "." || restored == ".." {
+        return Cow::Borrowed(component);
+    }
+    Cow::Owned(restored)
+}
+
+/// Whether the local filesystem can store names that are not valid UTF-8.
+/// Windows stores UTF-16 and APFS rejects them.
+pub const SUPPORTS_RAW_NAMES: bool = cfg!(all(unix, not(target_vendor = "apple")));
+
+/// Encode the raw bytes of a name component as a string without losing anything.
+///
+/// Characters from the escape range itself are escaped byte by byte, so
+/// [`decode_bytes`] gives back exactly `bytes`.
+pub fn encode_component(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if is_escaped_byte(c) {
+                encoded.extend(c.encode_utf8(&mut [0; 4]).bytes().map(escape_byte));
+            } else {
+                encoded.push(c);
+            }
+        }
+        encoded.extend(chunk.invalid().iter().copied().map(escape_byte));
+    }
+    encoded
+}
+
+/// Encode a local file name for a collection, see [`encode_component`].
+#[cfg(unix)]
+pub fn encode_os_str(name: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    encode_component(name.as_bytes())
+}
+
+/// Encode a local file name for a collection, see [`encode_component`].
+/// Windows names that are not valid UTF-16 are shared lossily.
+#[cfg(not(unix))]
+pub fn encode_os_str(name: &OsStr) -> String {
+    encode_component(name.to_string_lossy().as_bytes())
+}
+
+/// Whether `component` holds bytes escaped by [`encode_component`].
+pub fn has_escaped_bytes(component: &str) -> bool {
+    component.chars().any(is_escaped_byte)
+}
+
+/// The raw bytes `component` was encoded from.
+pub fn decode_bytes(component: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(component.len());
+    for c in component.chars() {
+        if is_escaped_byte(c) {
+            bytes.push((c as u32 - ESCAPE_BASE) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    bytes
+}
+
+/// A readable stand-in for a component with escaped bytes, for filesystems
+/// that can't store them. Bytes that are not valid UTF-8 are shown as `%XX`.
+pub fn readable_component(component: &str) -> Cow<'_, str> {
+    if !has_escaped_bytes(component) {
+        return Cow::Borrowed(component);
+    }
+    let bytes = decode_bytes(component);
+    let mut readable = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        readable.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            readable.push_str(&format!("%{b:02X}"));
+        }
+    }
+    Cow::Owned(readable)
+}
+
+/// The name to store `component` under on the local filesystem.
+pub fn decode_component(component: &str) -> OsString {
+    #[cfg(all(unix, not(target_vendor = "apple")))]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(decode_bytes(component))
+    }
+    #[cfg(not(all(unix, not(target_vendor = "apple"))))]
+    {
+        OsString::from(readable_component(component).into_owned())
+    }
+}
+
+/// Bring `name` into normalization form C. macOS hands out decomposed names,
+/// so without this the same name from two senders could differ byte-wise.
+pub fn normalize(name: &str) -> Cow<'_, str> {
+    if unicode_normalization::is_nfc(name) {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(name.nfc().collect())
+    }
+}
+
+/// The key under which a case-insensitive filesystem considers two names equal.
+pub fn collision_key(name: &str) -> String {
+    name.nfc().flat_map(char::to_lowercase).collect()
+}
+
+/// Pairs of distinct names that refer to the same file on a case-insensitive
+/// filesystem, e.g. `README.md` and `Readme.md`.
+pub fn case_collisions<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<(&'a str, &'a str)> {
+    let mut seen = BTreeMap::new();
+    let mut collisions = Vec::new();
+    for name in names {
+        match seen.entry(collision_key(name)) {
+            Entry::Vacant(entry) => {
+                entry.insert(name);
+            }
+            Entry::Occupied(entry) => collisions.push((*entry.get(), name)),
+        }
+    }
+    collisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(name: &str) -> String {
+        sanitize_component(name, Platform::Windows).into_owned()
+    }
+
+    #[test]
+    fn leaves_valid_names_alone() {
+        for name in [
+            "file.txt",
+            "Ünïcödé ☃",
+            ".hidden",
+            "a.b.c",
+            "CONSOLE.log",
+            "com10",
+        ] {
+            assert!(matches!(
+                sanitize_component(name, Platform::Windows),
+                Cow::Borrowed(_)
+            ));
+            assert!(matches!(
+                sanitize_component(name, Platform::Unix),
+                Cow::Borrowed(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn maps_reserved_characters() {
+        assert_eq!(windows("a:b"), "a\u{f03a}b");
+        assert_eq!(windows("what?*"), "what\u{f03f}\u{f02a}");
+        assert_eq!(windows("<|\">"), "\u{f03c}\u{f07c}\u{f022}\u{f03e}");
+        // unix can store all of these
+        assert_eq!(sanitize_component("a:b?", Platform::Unix), "a:b?");
+    }
+
+    #[test]
+    fn maps_trailing_dots_and_spaces() {
+        assert_eq!(windows("name."), "name\u{f02e}");
+        assert_eq!(windows("name. "), "name\u{f02e}\u{f020}");
+        assert_eq!(windows(" lead.ing"), " lead.ing");
+    }
+
+    #[test]
+    fn maps_reserved_device_names() {
+        assert_eq!(windows("CON"), "CO\u{f04e}");
+        assert_eq!(windows("aux.txt"), "au\u{f078}.txt");
+        assert_eq!(windows("Lpt1.tar.gz"), "Lpt\u{f031}.tar.gz");
+        assert_eq!(windows("nul .txt"), "nu\u{f06c} .txt");
+    }
+
+    #[test]
+    fn shortens_long_names_deterministically() {
+        let long = format!("{}.txt", "x".repeat(300));
+        let short = windows(&long);
+        assert!(short.len() <= MAX_NAME_BYTES);
+        assert!(short.ends_with(".txt"));
+        assert_eq!(short, sanitize_component(&long, Platform::Unix));
+
+        let other = format!("{}y.txt", "x".repeat(299));
+        assert_ne!(short, windows(&other));
+    }
+
+    #[test]
+    fn shortens_on_char_boundaries() {
+        let long = "☃".repeat(100);
+        let short = sanitize_component(&long, Platform::Unix);
+        assert!(short.len() <= MAX_NAME_BYTES);
+        assert!(short.starts_with('☃'));
+    }
+
+    #[test]
+    fn measures_the_stored_length_of_raw_names() {
+        // 200 bytes that are not UTF-8, each encoded as a three byte character
+        let name = encode_component(&[0xff; 200]);
+        assert_eq!(name.len(), 600);
+        let sanitized = sanitize_component(&name, Platform::Unix);
+        let stored = decode_component(&sanitized);
+        if SUPPORTS_RAW_NAMES {
+            assert_eq!(sanitized, name);
+            assert_eq!(stored.len(), 200);
+        } else {
+            assert!(readable_component(&sanitized).len() <= MAX_NAME_BYTES);
+        }
+
+        let long = encode_component(&[0xff; 300]);
+        let short = sanitize_component(&long, Platform::Unix);
+        assert_ne!(short, long);
+        assert!(decode_component(&short).len() <= MAX_NAME_BYTES);
+    }
+
+    #[test]
+    fn restores_mapped_names() {
+        for name in [
+            "a:b", "what?*", "name. ", "CON", "aux.txt", "<|\">", "plain",
+        ] {
+            assert_eq!(restore_component(&windows(name)), name);
+        }
+    }
+
+    #[test]
+    fn restores_only_what_the_mapping_produces() {
+        // would turn into a path separator, a NUL or `..`
+        for name in [
+            "x\u{f02f}y",
+            "x\u{f05c}y",
+            "nul\u{f000}",
+            "\u{f02e}\u{f02e}",
+        ] {
+            assert!(
+                matches!(restore_component(name), Cow::Borrowed(_)),
+                "{name:?}"
+            );
+        }
+        // an escaped dot that is not trailing, or a device letter that does
+        // not end a device name
+        assert_eq!(restore_component("a\u{f02e}b"), "a\u{f02e}b");
+        assert_eq!(restore_component("bo\u{f078}"), "bo\u{f078}");
+        assert_eq!(restore_component("\u{f03a}"), ":");
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        assert_eq!(normalize("cafe\u{301}"), "caf\u{e9}");
+        assert!(matches!(normalize("caf\u{e9}"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn finds_case_collisions() {
+        let names = [
+            "README.md",
+            "docs/a.txt",
+            "Readme.md",
+            "docs/A.txt",
+            "other",
+        ];
+        assert_eq!(
+            case_collisions(names),
+            [("README.md", "Readme.md"), ("docs/a.txt", "docs/A.txt")]
+        );
+        assert_eq!(case_collisions(["caf\u{e9}", "CAFE\u{301}"]).len(), 1);
+        assert!(case_collisions(["a", "b", "a.txt"]).is_empty());
+    }
+
+    #[test]
+    fn encodes_invalid_utf8_losslessly() {
+        let bytes = b"caf\xe9 \xff\xfe.txt";
+        let encoded = encode_component(bytes);
+        assert_eq!(encoded, "caf\u{f0e9} \u{f0ff}\u{f0fe}.txt");
+        assert!(has_escaped_bytes(&encoded));
+        assert_eq!(decode_bytes(&encoded), bytes);
+    }
+
+    #[test]
+    fn escapes_the_escape_range() {
+        let name = "a\u{f0e9}b";
+        let encoded = encode_component(name.as_bytes());
+        assert_ne!(encoded, name);
+        assert_eq!(decode_bytes(&encoded), name.as_bytes());
+        assert_eq!(readable_component(&encoded), name);
+    }
+
+    #[test]
+    fn round_trips_valid_names_with_escape_range_characters() {
+        for name in ["a\u{f0e9}b", "\u{f080}\u{f0ff}", "caf\u{e9} \u{f03a}.txt"] {
+            let encoded = encode_os_str(OsStr::new(name));
+            assert_eq!(decode_bytes(&encoded), name.as_bytes(), "{name:?}");
+            if SUPPORTS_RAW_NAMES {
+                assert_eq!(decode_component(&encoded), name, "{name:?}");
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn round_trips_raw_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9 \xef\x83\xa9.txt");
+        let encoded = encode_os_str(name);
+        assert_eq!(decode_bytes(&encoded), name.as_bytes());
+        if SUPPORTS_RAW_NAMES {
+            assert_eq!(decode_component(&encoded), name);
+        }
+    }
+
+    #[test]
+    fn leaves_valid_utf8_alone() {
+        let name = "Ünïcödé \u{f03a}.txt";
+        assert_eq!(encode_component(name.as_bytes()), name);
+        assert!(!has_escaped_bytes(name));
+        assert_eq!(decode_bytes(name), name.as_bytes());
+    }
+
+    #[test]
+    fn falls_back_to_a_readable_form() {
+        let encoded = encode_component(b"caf\xe9 caf\xc3\xa9");
+        assert_eq!(readable_component(&encoded), "caf%E9 caf\u{e9}");
+        assert!(matches!(readable_component("plain"), Cow::Borrowed(_)));
+    }
+}
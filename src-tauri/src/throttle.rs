@@ -0,0 +1,443 @@
+//! Download bandwidth limiting.
+//!
+//! `get_to_db` reads from the connection as fast as it can and reports every
+//! write through its progress sender, with the offset the write starts at.
+//! [`ThrottledProgress`] wraps that sender and pauses the download task
+//! whenever it gets ahead of the configured rate.
+//! While the task is paused nothing is read from the stream, so QUIC flow
+//! control pushes the back pressure all the way to the sender.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use iroh_blobs::{
+    get::db::DownloadProgress,
+    util::progress::{IdGenerator, ProgressSendResult, ProgressSender},
+};
+use tokio::{runtime::RuntimeFlavor, sync::Mutex};
+
+use crate::transfers::{CancelHandle, TransferId};
+
+/// Sentinel stored in [`RateLimiter::limit`] when it follows the global default.
+const INHERIT: u64 = u64::MAX;
+
+/// How far ahead of the configured rate a download may burst.
+const MAX_BURST: Duration = Duration::from_millis(250);
+
+/// Longest single pause. A pause is taken in slices of at most this long so
+/// that a new limit or a cancelled receive takes effect right away.
+const MAX_PAUSE: Duration = Duration::from_millis(50);
+
+/// The most a single write of `get_to_db` can add, one chunk group of the store.
+const MAX_WRITE: u64 = 16 * 1024;
+
+/// Global default limit in bytes per second, `0` means unlimited.
+static DEFAULT_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// Limiters of the receives that are currently running.
+static LIMITERS: Mutex<BTreeMap<TransferId, Arc<RateLimiter>>> = Mutex::const_new(BTreeMap::new());
+
+/// Bytes received but not paid for yet.
+#[derive(Debug)]
+struct Debt {
+    bytes: f64,
+    updated: Instant,
+}
+
+/// A token bucket that can be reconfigured while a transfer is running.
+///
+/// The debt is kept in bytes rather than time, so a new limit also applies
+/// to what was received before the change.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes per second, `0` for unlimited or [`INHERIT`] for the global default.
+    limit: AtomicU64,
+    debt: std::sync::Mutex<Debt>,
+}
+
+impl RateLimiter {
+    /// Create a limiter. `None` follows the global default, `Some(0)` disables throttling.
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit: AtomicU64::new(limit.unwrap_or(INHERIT)),
+            debt: std::sync::Mutex::new(Debt {
+                bytes: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit
+            .store(limit.unwrap_or(INHERIT), Ordering::Relaxed);
+    }
+
+    /// The limit currently in effect, or `None` if the download is unthrottled.
+    pub fn effective_limit(&self) -> Option<u64> {
+        let limit = match self.limit.load(Ordering::Relaxed) {
+            INHERIT => DEFAULT_LIMIT.load(Ordering::Relaxed),
+            limit => limit,
+        };
+        (limit > 0).then_some(limit)
+    }
+
+    /// Account for `bytes` that were just received and return how long the
+    /// caller has to pause to stay within the limit. `consume(0)` tells how
+    /// much of an earlier pause is left under the current limit.
+    pub fn consume(&self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let mut debt = self.debt.lock().unwrap();
+        let elapsed = now.saturating_duration_since(debt.updated);
+        debt.updated = now;
+        let Some(limit) = self.effective_limit() else {
+            debt.bytes = 0.0;
+            return Duration::ZERO;
+        };
+        let limit = limit as f64;
+        debt.bytes = (debt.bytes - elapsed.as_secs_f64() * limit).max(0.0) + bytes as f64;
+        let ahead = debt.bytes - MAX_BURST.as_secs_f64() * limit;
+        if ahead <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(ahead / limit)
+    }
+}
+
+/// Set the limit used by every receive that has no limit of its own.
+pub fn set_default_limit(limit: Option<u64>) {
+    DEFAULT_LIMIT.store(limit.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The limit receives without their own limit use, `None` if unlimited.
+pub fn default_limit() -> Option<u64> {
+    let limit = DEFAULT_LIMIT.load(Ordering::Relaxed);
+    (limit > 0).then_some(limit)
+}
+
+/// Create the limiter for a receive and make it reachable for [`set_limit`].
+pub async fn register(id: TransferId, limit: Option<u64>) -> Arc<RateLimiter> {
+    let limiter = Arc::new(RateLimiter::new(limit));
+    LIMITERS.lock().await.insert(id, limiter.clone());
+    limiter
+}
+
+pub async fn unregister(id: TransferId) {
+    LIMITERS.lock().await.remove(&id);
+}
+
+/// Change the limit of a running receive. Returns false if there is none with `id`.
+pub async fn set_limit(id: TransferId, limit: Option<u64>) -> bool {
+    match LIMITERS.lock().await.get(&id) {
+        Some(limiter) => {
+            limiter.set_limit(limit);
+            true
+        }
+        None => false,
+    }
+}
+
+/// What is known about a blob being fetched, by its progress id.
+#[derive(Debug, Default)]
+struct BlobWrites {
+    /// From `Found`, missing if the events started later.
+    size: Option<u64>,
+    /// Where the last reported write started. Its length is only known once
+    /// the next write starts or the blob is done.
+    last: Option<u64>,
+}
+
+/// A progress sender that throttles the download it is reporting on.
+///
+/// Writes are reported through the synchronous [`ProgressSender::try_send`].
+/// On a multi threaded runtime it pauses with `block_in_place`. A current
+/// thread runtime can't be blocked without stalling everything else on it,
+/// so there the debt is paid by the next asynchronous [`ProgressSender::send`]
+/// instead, at the latest when the blob is done. Pauses end early once
+/// `cancel` is cancelled, since the task that would notice is the one being
+/// paused.
+#[derive(Debug, Clone)]
+pub struct ThrottledProgress<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    cancel: CancelHandle,
+    blobs: Arc<std::sync::Mutex<BTreeMap<u64, BlobWrites>>>,
+    writes: Arc<AtomicU64>,
+}
+
+impl<S> ThrottledProgress<S> {
+    pub fn new(inner: S, limiter: Arc<RateLimiter>, cancel: CancelHandle) -> Self {
+        Self {
+            inner,
+            limiter,
+            cancel,
+            blobs: Default::default(),
+            writes: Default::default(),
+        }
+    }
+
+    /// The number of writes reported so far, shared by every clone.
+    pub fn writes(&self) -> Arc<AtomicU64> {
+        self.writes.clone()
+    }
+
+    /// Bytes of the writes that are known to be complete with `msg`.
+    ///
+    /// A write is charged once the next one starts, and the last one of a
+    /// blob when it is done. No write is longer than [`MAX_WRITE`], so the
+    /// ranges that were in the store already, after a resume or a reconnect,
+    /// are skipped rather than charged.
+    fn received(&self, msg: &DownloadProgress) -> u64 {
+        let mut blobs = self.blobs.lock().unwrap();
+        let (id, end) = match msg {
+            DownloadProgress::Found { id, size, .. } => {
+                blobs.entry(*id).or_default().size = Some(*size);
+                return 0;
+            }
+            DownloadProgress::Progress { id, offset } => {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+                (*id, Some(*offset))
+            }
+            DownloadProgress::Done { id } => (*id, None),
+            _ => return 0,
+        };
+        let blob = blobs.entry(id).or_default();
+        let written = match (blob.last, end.or(blob.size)) {
+            (Some(start), Some(end)) => end.saturating_sub(start).min(MAX_WRITE),
+            _ => 0,
+        };
+        match end {
+            Some(offset) => blob.last = Some(offset),
+            None => {
+                blobs.remove(&id);
+            }
+        }
+        written
+    }
+
+    /// The next slice of the pause owed for `bytes`, `None` once there is
+    /// nothing left to wait for.
+    fn pause(&self, bytes: u64) -> Option<Duration> {
+        let delay = self.limiter.consume(bytes);
+        if delay.is_zero() || self.cancel.is_cancelled() {
+            return None;
+        }
+        Some(delay.min(MAX_PAUSE))
+    }
+
+    fn wait_blocking(&self, first: Duration) {
+        let mut pause = Some(first);
+        while let Some(slice) = pause {
+            std::thread::sleep(slice);
+            pause = self.pause(0);
+        }
+    }
+}
+
+impl<S> ProgressSender for ThrottledProgress<S>
+where
+    S: ProgressSender<Msg = DownloadProgress>,
+{
+    type Msg = DownloadProgress;
+
+    async fn send(&self, msg: Self::Msg) -> ProgressSendResult<()> {
+        let mut pause = self.pause(self.received(&msg));
+        while let Some(slice) = pause {
+            tokio::time::sleep(slice).await;
+            pause = self.pause(0);
+        }
+        self.inner.send(msg).await
+    }
+
+    fn try_send(&self, msg: Self::Msg) -> ProgressSendResult<()> {
+        let bytes = self.received(&msg);
+        let multi_thread = tokio::runtime::Handle::try_current()
+            .is_ok_and(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread);
+        if !multi_thread {
+            // the debt stays with the limiter until the next async send
+            self.limiter.consume(bytes);
+        } else if let Some(first) = self.pause(bytes) {
+            tokio::task::block_in_place(|| self.wait_blocking(first));
+        }
+        self.inner.try_send(msg)
+    }
+
+    fn blocking_send(&self, msg: Self::Msg) -> ProgressSendResult<()> {
+        if let Some(first) = self.pause(self.received(&msg)) {
+            self.wait_blocking(first);
+        }
+        self.inner.blocking_send(msg)
+    }
+}
+
+impl<S: IdGenerator> IdGenerator for ThrottledProgress<S> {
+    fn new_id(&self) -> u64 {
+        self.inner.new_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_blobs::{get::db::BlobId, util::progress::IgnoreProgressSender, Hash};
+
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// `actual` is `expected`, give or take the time the test itself takes.
+    fn assert_about(actual: Duration, expected: Duration) {
+        let slack = Duration::from_millis(20);
+        assert!(
+            actual <= expected && actual + slack >= expected,
+            "{actual:?} is not about {expected:?}"
+        );
+    }
+
+    #[test]
+    fn unlimited_never_pauses() {
+        let limiter = RateLimiter::new(Some(0));
+        assert_eq!(limiter.consume(u64::MAX / 2), Duration::ZERO);
+        assert_eq!(limiter.consume(1 << 40), Duration::ZERO);
+    }
+
+    #[test]
+    fn bursts_without_pausing() {
+        let limiter = RateLimiter::new(Some(1000));
+        // a quarter of a second worth of data
+        assert_eq!(limiter.consume(200), Duration::ZERO);
+        assert_eq!(limiter.consume(50), Duration::ZERO);
+        assert!(!limiter.consume(100).is_zero());
+    }
+
+    #[test]
+    fn pauses_for_what_exceeds_the_burst() {
+        let limiter = RateLimiter::new(Some(1000));
+        assert_about(limiter.consume(1250), SECOND);
+        // the pause is not charged twice
+        assert_about(limiter.consume(0), SECOND);
+        assert_about(limiter.consume(1000), 2 * SECOND);
+    }
+
+    #[test]
+    fn new_limit_applies_to_earlier_debt() {
+        let limiter = RateLimiter::new(Some(1000));
+        assert_about(limiter.consume(10_250), 10 * SECOND);
+        limiter.set_limit(Some(100_000));
+        assert_eq!(limiter.consume(0), Duration::ZERO);
+
+        let limiter = RateLimiter::new(Some(100_000));
+        assert_eq!(limiter.consume(25_000), Duration::ZERO);
+        limiter.set_limit(Some(1000));
+        assert_about(limiter.consume(0), 24_750 * SECOND / 1000);
+    }
+
+    #[test]
+    fn removing_the_limit_forgives_the_debt() {
+        let limiter = RateLimiter::new(Some(1000));
+        assert!(!limiter.consume(100_000).is_zero());
+        limiter.set_limit(Some(0));
+        assert_eq!(limiter.consume(0), Duration::ZERO);
+        limiter.set_limit(Some(1000));
+        assert_eq!(limiter.consume(0), Duration::ZERO);
+    }
+
+    fn throttled(limit: u64) -> ThrottledProgress<IgnoreProgressSender<DownloadProgress>> {
+        ThrottledProgress::new(
+            IgnoreProgressSender::default(),
+            Arc::new(RateLimiter::new(Some(limit))),
+            CancelHandle::default(),
+        )
+    }
+
+    fn found(id: u64, size: u64) -> DownloadProgress {
+        DownloadProgress::Found {
+            id,
+            child: BlobId::Root,
+            hash: Hash::new(id.to_le_bytes()),
+            size,
+        }
+    }
+
+    fn at(id: u64, offset: u64) -> DownloadProgress {
+        DownloadProgress::Progress { id, offset }
+    }
+
+    fn done(id: u64) -> DownloadProgress {
+        DownloadProgress::Done { id }
+    }
+
+    #[test]
+    fn charges_every_write_including_the_last() {
+        let progress = throttled(1_000_000);
+        // events carry the offset a write starts at
+        assert_eq!(progress.received(&found(1, 40_000)), 0);
+        assert_eq!(progress.received(&at(1, 0)), 0);
+        assert_eq!(progress.received(&at(1, 16_384)), 16_384);
+        assert_eq!(progress.received(&at(1, 32_768)), 16_384);
+        assert_eq!(progress.received(&done(1)), 40_000 - 32_768);
+
+        // a blob that fits in a single write
+        assert_eq!(progress.received(&found(2, 1000)), 0);
+        assert_eq!(progress.received(&at(2, 0)), 0);
+        assert_eq!(progress.received(&done(2)), 1000);
+    }
+
+    #[test]
+    fn resumed_blobs_are_charged_for_new_data_only() {
+        let progress = throttled(1_000_000);
+        // 9 GB were in the store before the first write of the blob
+        progress.received(&found(1, 9_000_100_000));
+        assert_eq!(progress.received(&at(1, 9_000_016_384)), 0);
+        assert_eq!(progress.received(&at(1, 9_000_032_768)), 16_384);
+        // a range that was in the store is skipped
+        assert_eq!(progress.received(&at(1, 9_000_098_304)), MAX_WRITE);
+        assert_eq!(progress.received(&done(1)), 1_696);
+        // a reconnect reports the same blob under a new id
+        assert_eq!(progress.received(&at(2, 9_000_049_152)), 0);
+        assert_eq!(progress.received(&at(2, 9_000_065_536)), 16_384);
+        assert_eq!(progress.pause(0), None);
+    }
+
+    #[test]
+    fn many_small_blobs_are_throttled() {
+        let progress = throttled(1000);
+        for id in 0..10 {
+            progress.received(&found(id, 100));
+            progress.received(&at(id, 0));
+            progress.limiter.consume(progress.received(&done(id)));
+        }
+        // 1000 bytes against a burst of 250
+        assert!(progress.pause(0).is_some());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn current_thread_runtimes_pay_on_the_next_send() {
+        let progress = throttled(1000);
+        progress.send(found(1, 1250)).await.unwrap();
+        progress.try_send(at(1, 0)).unwrap();
+        // try_send must not block a current thread runtime
+        progress.try_send(at(1, 1250)).unwrap();
+        let started = Instant::now();
+        progress.send(done(1)).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn pauses_are_sliced_and_end_on_cancel() {
+        let limiter = Arc::new(RateLimiter::new(Some(1000)));
+        let cancel = CancelHandle::default();
+        let progress = ThrottledProgress::new(
+            IgnoreProgressSender::<DownloadProgress>::default(),
+            limiter.clone(),
+            cancel.clone(),
+        );
+        assert_eq!(progress.pause(100_000), Some(MAX_PAUSE));
+        cancel.cancel(false);
+        assert_eq!(progress.pause(0), None);
+    }
+}
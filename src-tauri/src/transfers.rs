@@ -0,0 +1,103 @@
+//! Bookkeeping for transfers that run in the background.
+//!
+//! Long running commands return a [`TransferId`] right away and do their work
+//! in a spawned task. The task registers a [`CancelHandle`] here so that a
+//! later command can stop it.
+//!
+//! Ids are unique within the process. Code that runs transfers without
+//! registering them here takes its ids from [`new_transfer_id`] as well.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+pub type TransferId = u64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A transfer id that no other transfer of this process has.
+pub fn new_transfer_id() -> TransferId {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Running receives.
+pub static RECEIVES: Transfers = Transfers::new();
+
+/// Shares that are still importing and have not published a ticket yet.
+pub static SHARES: Transfers = Transfers::new();
+
+/// Allows a running transfer to be cancelled from another command.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    token: CancellationToken,
+    keep_partial: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn cancel(&self, keep_partial: bool) {
+        self.keep_partial.store(keep_partial, Ordering::Relaxed);
+        self.token.cancel();
+    }
+
+    /// Completes once [`CancelHandle::cancel`] has been called.
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Whether the canceller asked to keep partially transferred data.
+    pub fn keep_partial(&self) -> bool {
+        self.keep_partial.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Transfers {
+    running: Mutex<BTreeMap<TransferId, CancelHandle>>,
+}
+
+impl Transfers {
+    const fn new() -> Self {
+        Self {
+            running: Mutex::const_new(BTreeMap::new()),
+        }
+    }
+
+    /// Register a new transfer and return its id and cancel handle.
+    pub async fn insert(&self) -> (TransferId, CancelHandle) {
+        let id = new_transfer_id();
+        let handle = CancelHandle::default();
+        self.running.lock().await.insert(id, handle.clone());
+        (id, handle)
+    }
+
+    pub async fn remove(&self, id: TransferId) {
+        self.running.lock().await.remove(&id);
+    }
+
+    /// Ids of the transfers that are running.
+    pub async fn ids(&self) -> Vec<TransferId> {
+        self.running.lock().await.keys().copied().collect()
+    }
+
+    /// Cancel the transfer with the given id. Returns false if it is not running.
+    pub async fn cancel(&self, id: TransferId, keep_partial: bool) -> bool {
+        match self.running.lock().await.get(&id) {
+            Some(handle) => {
+                handle.cancel(keep_partial);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,607 @@
+//! End-to-end transfers between a sender and a receiver in the same process.
+//!
+//! Both sides use [`Network::Loopback`], so the tests never touch a relay
+//! or the internet.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sendme_desktop_lib::{
+    inspect, is_resumable, new_transfer_id, receive, receive_text, share, share_bytes,
+    CancelHandle, ExportAction, HistoryEntry, ImportProgressUpdate, Network, ProgressPhase,
+    ProgressSink, ProgressUpdate, ReceiveOptions, Received, Reconnecting, Selection,
+    SwiftsendError, TransferContext, MAX_TEXT_SIZE,
+};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn import_progress(&self, _update: ImportProgressUpdate) {}
+    fn download_progress(&self, _update: ProgressUpdate) {}
+    fn reconnecting(&self, _event: Reconnecting) {}
+}
+
+/// Cancels a receive as soon as it starts writing the files out.
+struct CancelOnExport {
+    cancel: CancelHandle,
+    keep_partial: bool,
+}
+
+impl ProgressSink for CancelOnExport {
+    fn import_progress(&self, _update: ImportProgressUpdate) {}
+
+    fn download_progress(&self, update: ProgressUpdate) {
+        if update.phase == ProgressPhase::Exporting {
+            self.cancel.cancel(self.keep_partial);
+        }
+    }
+
+    fn reconnecting(&self, _event: Reconnecting) {}
+}
+
+/// A scratch directory with the sources, the destination and the state of
+/// both sides.
+struct Harness {
+    root: TempDir,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let root = tempfile::tempdir().unwrap();
+        for dir in ["sources", "received", "sender", "receiver", "stores"] {
+            std::fs::create_dir(root.path().join(dir)).unwrap();
+        }
+        Self { root }
+    }
+
+    fn sources(&self) -> PathBuf {
+        self.root.path().join("sources")
+    }
+
+    fn received(&self) -> PathBuf {
+        self.root.path().join("received")
+    }
+
+    fn context(&self, side: &str) -> TransferContext {
+        self.context_with(side, Arc::new(NoProgress))
+    }
+
+    fn context_with(&self, side: &str, sink: Arc<dyn ProgressSink>) -> TransferContext {
+        TransferContext {
+            data_dir: self.root.path().join(side),
+            store_dir: self.root.path().join("stores"),
+            network: Network::Loopback,
+            sink,
+        }
+    }
+
+    /// Share `paths` and receive them into [`Harness::received`].
+    async fn transfer(&self, paths: Vec<PathBuf>, options: ReceiveOptions) -> Received {
+        let sender = self.context("sender");
+        let receiver = self.context("receiver");
+        let share = share(&sender, new_transfer_id(), paths, &CancelHandle::default())
+            .await
+            .expect("share");
+        let result = receive(
+            &receiver,
+            new_transfer_id(),
+            share.ticket().clone(),
+            self.received(),
+            options,
+            &CancelHandle::default(),
+        )
+        .await;
+        share.stop(&sender.data_dir).await.expect("stop share");
+        result.expect("receive")
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, data).unwrap();
+}
+
+fn random_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    rng.fill(&mut data[..]);
+    data
+}
+
+/// Files below `root`, by their path relative to it.
+fn files(root: &Path) -> Vec<PathBuf> {
+    WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .map(Result::unwrap)
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.path().strip_prefix(root).unwrap().to_path_buf())
+        .collect()
+}
+
+/// Staging directories of exports left behind in `destination`.
+fn staging_dirs(destination: &Path) -> usize {
+    let Ok(entries) = std::fs::read_dir(destination.join(".sendme")) else {
+        return 0;
+    };
+    entries
+        .map(Result::unwrap)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(".sendme-export-")
+        })
+        .count()
+}
+
+/// Check that `actual` holds exactly the files of `expected`, byte for byte.
+fn assert_same_tree(expected: &Path, actual: &Path) {
+    let expected_files = files(expected);
+    assert_eq!(expected_files, files(actual), "file names differ");
+    for file in expected_files {
+        let want = std::fs::read(expected.join(&file)).unwrap();
+        let got = std::fs::read(actual.join(&file)).unwrap();
+        assert!(want == got, "{} differs", file.display());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nested_directories() {
+    let harness = Harness::new();
+    let mut rng = StdRng::seed_from_u64(1);
+    let tree = harness.sources().join("tree");
+    for (i, dir) in ["", "a", "a/b", "a/b/c", "a/b/c/d", "e", "e/f"]
+        .iter()
+        .enumerate()
+    {
+        for j in 0..3 {
+            let len = rng.gen_range(1..64 * 1024);
+            write_file(
+                &tree.join(dir).join(format!("file-{i}-{j}.bin")),
+                &random_bytes(&mut rng, len),
+            );
+        }
+    }
+
+    let received = harness
+        .transfer(vec![tree.clone()], ReceiveOptions::default())
+        .await;
+
+    assert_eq!(received.report.files.len(), 21);
+    assert!(received
+        .report
+        .files
+        .iter()
+        .all(|file| file.action == ExportAction::Written));
+    assert_same_tree(&tree, &harness.received().join("tree"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_file() {
+    let harness = Harness::new();
+    let mut rng = StdRng::seed_from_u64(2);
+    // not a multiple of the chunk group size, so the last group is partial
+    let data = random_bytes(&mut rng, 20 * 1024 * 1024 + 12_345);
+    let file = harness.sources().join("large.bin");
+    write_file(&file, &data);
+
+    let received = harness
+        .transfer(vec![file], ReceiveOptions::default())
+        .await;
+
+    assert_eq!(received.size, data.len() as u64);
+    assert!(std::fs::read(harness.received().join("large.bin")).unwrap() == data);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn many_small_files() {
+    let harness = Harness::new();
+    let mut rng = StdRng::seed_from_u64(3);
+    let tree = harness.sources().join("small");
+    for i in 0..500 {
+        let len = rng.gen_range(0..2048);
+        write_file(
+            &tree.join(format!("{}/{i}.txt", i % 10)),
+            &random_bytes(&mut rng, len),
+        );
+    }
+    write_file(&tree.join("empty"), b"");
+
+    let received = harness
+        .transfer(vec![tree.clone()], ReceiveOptions::default())
+        .await;
+
+    assert_eq!(received.report.files.len(), 501);
+    assert_same_tree(&tree, &harness.received().join("small"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn odd_names() {
+    let harness = Harness::new();
+    let tree = harness.sources().join("odd names");
+    let names = [
+        "with space.txt",
+        "  leading spaces",
+        ".hidden",
+        "no extension",
+        "many.dots.in.name.tar.gz",
+        "[brackets] (parens) {braces}",
+        "percent %20 hash # plus + amp &",
+        "caf\u{e9}.txt",
+        "\u{65e5}\u{672c}\u{8a9e}/\u{30d5}\u{30a1}\u{30a4}\u{30eb}.txt",
+        "emoji \u{1f680}/\u{1f4e6}.bin",
+        "-starts-with-dash",
+    ];
+    for name in names {
+        write_file(&tree.join(name), name.as_bytes());
+    }
+
+    let received = harness
+        .transfer(vec![tree.clone()], ReceiveOptions::default())
+        .await;
+
+    assert_eq!(received.report.files.len(), names.len());
+    assert_same_tree(&tree, &harness.received().join("odd names"));
+}
+
+/// Names that are not valid UTF-8 arrive byte for byte where the
+/// filesystem can store them.
+#[cfg(all(unix, not(target_vendor = "apple")))]
+#[tokio::test(flavor = "multi_thread")]
+async fn non_utf8_names() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let harness = Harness::new();
+    let tree = harness.sources().join("raw names");
+    // latin-1, a lone continuation byte and the longest name there is,
+    // which is far longer than that once encoded for the collection
+    let names: [&[u8]; 4] = [
+        b"caf\xe9.txt",
+        b"\x80\xff/inside \xfe.bin",
+        b"valid/\xc3\x28",
+        &[0xe9; 255],
+    ];
+    for name in names {
+        write_file(&tree.join(OsStr::from_bytes(name)), name);
+    }
+
+    let received = harness
+        .transfer(vec![tree.clone()], ReceiveOptions::default())
+        .await;
+
+    assert_eq!(received.report.files.len(), names.len());
+    assert!(received.report.files.iter().all(|file| !file.sanitized));
+    assert_same_tree(&tree, &harness.received().join("raw names"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn several_paths() {
+    let harness = Harness::new();
+    let mut rng = StdRng::seed_from_u64(4);
+    let file = harness.sources().join("single.bin");
+    write_file(&file, &random_bytes(&mut rng, 100_000));
+    let dir = harness.sources().join("dir");
+    write_file(&dir.join("inner/one.bin"), &random_bytes(&mut rng, 10));
+    write_file(&dir.join("two.bin"), &random_bytes(&mut rng, 20_000));
+
+    harness
+        .transfer(vec![file, dir], ReceiveOptions::default())
+        .await;
+
+    assert_same_tree(&harness.sources(), &harness.received());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn selection() {
+    let harness = Harness::new();
+    let mut rng = StdRng::seed_from_u64(5);
+    let tree = harness.sources().join("project");
+    write_file(&tree.join("docs/guide.md"), &random_bytes(&mut rng, 3_000));
+    write_file(
+        &tree.join("docs/api/index.md"),
+        &random_bytes(&mut rng, 5_000),
+    );
+    write_file(&tree.join("src/main.rs"), &random_bytes(&mut rng, 7_000));
+    write_file(&tree.join("notes.txt"), &random_bytes(&mut rng, 11));
+
+    let patterns = ["project/docs".to_string(), "project/*.txt".to_string()];
+    let options = ReceiveOptions {
+        select: Some(Selection::new(&patterns)),
+        ..Default::default()
+    };
+    let received = harness.transfer(vec![tree.clone()], options).await;
+
+    assert_eq!(received.report.files.len(), 3);
+    let project = harness.received().join("project");
+    assert_eq!(
+        files(&project),
+        [
+            PathBuf::from("docs/api/index.md"),
+            PathBuf::from("docs/guide.md"),
+            PathBuf::from("notes.txt"),
+        ]
+    );
+    for file in files(&project) {
+        assert!(
+            std::fs::read(tree.join(&file)).unwrap() == std::fs::read(project.join(&file)).unwrap()
+        );
+    }
+}
+
+// both names can only exist on a case-sensitive filesystem
+#[cfg(not(any(target_os = "macos", windows)))]
+#[tokio::test(flavor = "multi_thread")]
+async fn share_reports_case_collisions() {
+    let harness = Harness::new();
+    let tree = harness.sources().join("tree");
+    write_file(&tree.join("README.md"), b"upper");
+    write_file(&tree.join("Readme.md"), b"mixed");
+    write_file(&tree.join("other.md"), b"other");
+
+    let sender = harness.context("sender");
+    let share = share(
+        &sender,
+        new_transfer_id(),
+        vec![tree],
+        &CancelHandle::default(),
+    )
+    .await
+    .expect("share");
+    let collisions = share.case_collisions().to_vec();
+    share.stop(&sender.data_dir).await.expect("stop share");
+
+    assert_eq!(
+        collisions,
+        [("tree/README.md".to_string(), "tree/Readme.md".to_string())]
+    );
+}
+
+// macOS normalizes names, so only one of the two can exist there, and a
+// case-insensitive receiver writes them next to each other
+#[cfg(not(any(target_os = "macos", windows)))]
+#[tokio::test(flavor = "multi_thread")]
+async fn normalization_twins_are_both_shared() {
+    let harness = Harness::new();
+    let tree = harness.sources().join("tree");
+    write_file(&tree.join("caf\u{e9}.txt"), b"composed");
+    write_file(&tree.join("cafe\u{301}.txt"), b"decomposed");
+
+    let received = harness
+        .transfer(vec![tree.clone()], ReceiveOptions::default())
+        .await;
+
+    assert_eq!(received.report.files.len(), 2);
+    assert_same_tree(&tree, &harness.received().join("tree"));
+}
+
+/// The completed downloads the sender recorded for its only share.
+fn completed_downloads(harness: &Harness) -> u64 {
+    let path = harness.root.path().join("sender").join("history.json");
+    let entries: Vec<HistoryEntry> = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+    match entries.as_slice() {
+        [HistoryEntry::Send(record)] => record.completed_downloads,
+        other => panic!("unexpected history {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn one_receive_counts_one_download() {
+    let harness = Harness::new();
+    let mut rng = StdRng::seed_from_u64(7);
+    let tree = harness.sources().join("tree");
+    for i in 0..4 {
+        write_file(
+            &tree.join(format!("file-{i}.bin")),
+            &random_bytes(&mut rng, 100_000),
+        );
+    }
+
+    let sender = harness.context("sender");
+    let share = share(
+        &sender,
+        new_transfer_id(),
+        vec![tree],
+        &CancelHandle::default(),
+    )
+    .await
+    .expect("share");
+    // looking at the files first is not a download
+    let manifest = inspect(share.ticket(), Network::Loopback)
+        .await
+        .expect("inspect");
+    assert_eq!(manifest.files.len(), 4);
+    receive(
+        &harness.context("receiver"),
+        new_transfer_id(),
+        share.ticket().clone(),
+        harness.received(),
+        ReceiveOptions::default(),
+        &CancelHandle::default(),
+    )
+    .await
+    .expect("receive");
+
+    // the history is updated in the background
+    for _ in 0..50 {
+        if completed_downloads(&harness) > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    share.stop(&sender.data_dir).await.expect("stop share");
+    assert_eq!(completed_downloads(&harness), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn text_from_memory() {
+    let harness = Harness::new();
+    let sender = harness.context("sender");
+    let text = "token: 0123456789abcdef\nhttps://example.com/\u{1f680}\n".to_string();
+    let share = share_bytes(
+        &sender,
+        new_transfer_id(),
+        "snippet.txt".to_string(),
+        text.clone().into_bytes(),
+        &CancelHandle::default(),
+    )
+    .await
+    .expect("share");
+    assert!(share.blobs_data_dir().is_none());
+
+    let result = receive_text(share.ticket(), Network::Loopback).await;
+    share.stop(&sender.data_dir).await.expect("stop share");
+
+    let received = result.expect("receive text");
+    assert_eq!(received.name, "snippet.txt");
+    assert_eq!(received.text, text);
+    // only the history of the sender is written
+    assert!(files(&harness.root.path().join("stores")).is_empty());
+    assert!(files(&harness.received()).is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn text_rejects_large_and_binary_payloads() {
+    let harness = Harness::new();
+    let sender = harness.context("sender");
+    let payloads = [
+        ("large.txt", vec![b'a'; MAX_TEXT_SIZE as usize + 1]),
+        ("binary.bin", vec![0xff, 0xfe, 0x00, 0x80]),
+    ];
+    for (name, data) in payloads {
+        let share = share_bytes(
+            &sender,
+            new_transfer_id(),
+            name.to_string(),
+            data,
+            &CancelHandle::default(),
+        )
+        .await
+        .expect("share");
+        let result = receive_text(share.ticket(), Network::Loopback).await;
+        share.stop(&sender.data_dir).await.expect("stop share");
+        assert!(
+            matches!(result, Err(SwiftsendError::InvalidArgument(_))),
+            "{name}: {result:?}"
+        );
+    }
+}
+
+/// A tree that takes long enough to export to be cancelled half way.
+fn large_tree(harness: &Harness) -> PathBuf {
+    let mut rng = StdRng::seed_from_u64(6);
+    let tree = harness.sources().join("large");
+    for i in 0..6 {
+        write_file(
+            &tree.join(format!("{i}.bin")),
+            &random_bytes(&mut rng, 8 * 1024 * 1024),
+        );
+    }
+    tree
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resume_after_cancel_during_export() {
+    let harness = Harness::new();
+    let tree = large_tree(&harness);
+    let sender = harness.context("sender");
+    let share = share(
+        &sender,
+        new_transfer_id(),
+        vec![tree.clone()],
+        &CancelHandle::default(),
+    )
+    .await
+    .expect("share");
+
+    let cancel = CancelHandle::default();
+    let sink = CancelOnExport {
+        cancel: cancel.clone(),
+        keep_partial: true,
+    };
+    let receiver = harness.context_with("receiver", Arc::new(sink));
+    let result = receive(
+        &receiver,
+        new_transfer_id(),
+        share.ticket().clone(),
+        harness.received(),
+        ReceiveOptions::default(),
+        &cancel,
+    )
+    .await;
+    assert!(
+        matches!(result, Err(SwiftsendError::Cancelled)),
+        "{result:?}"
+    );
+    assert_eq!(staging_dirs(&harness.received()), 0);
+    assert!(
+        is_resumable(&receiver.data_dir, share.ticket(), &harness.received())
+            .await
+            .unwrap()
+    );
+
+    // the store was kept intact by the cancelled export
+    let receiver = harness.context("receiver");
+    let result = receive(
+        &receiver,
+        new_transfer_id(),
+        share.ticket().clone(),
+        harness.received(),
+        ReceiveOptions::default(),
+        &CancelHandle::default(),
+    )
+    .await;
+    share.stop(&sender.data_dir).await.expect("stop share");
+    result.expect("resume");
+    assert_same_tree(&tree, &harness.received().join("large"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancel_during_export_discards_everything() {
+    let harness = Harness::new();
+    let tree = large_tree(&harness);
+    let sender = harness.context("sender");
+    let share = share(
+        &sender,
+        new_transfer_id(),
+        vec![tree],
+        &CancelHandle::default(),
+    )
+    .await
+    .expect("share");
+
+    let cancel = CancelHandle::default();
+    let sink = CancelOnExport {
+        cancel: cancel.clone(),
+        keep_partial: false,
+    };
+    let receiver = harness.context_with("receiver", Arc::new(sink));
+    let result = receive(
+        &receiver,
+        new_transfer_id(),
+        share.ticket().clone(),
+        harness.received(),
+        ReceiveOptions::default(),
+        &cancel,
+    )
+    .await;
+    let ticket = share.ticket().clone();
+    share.stop(&sender.data_dir).await.expect("stop share");
+
+    assert!(
+        matches!(result, Err(SwiftsendError::Cancelled)),
+        "{result:?}"
+    );
+    assert!(files(&harness.received()).is_empty());
+    assert!(
+        !is_resumable(&receiver.data_dir, &ticket, &harness.received())
+            .await
+            .unwrap()
+    );
+}
@@ -1,4 +1,6 @@
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import { useEffect } from "react";
 import { DragAndDrop } from "./components/DragAndDrop";
 import { FileProvier, useFile } from "./contexts/FileContext";
 import { Receive } from "./components/Receive";
@@ -11,11 +13,25 @@ function MainContent() {
   const shareFileHandler = async () => {
     if (connected) return;
     setConnected(true);
-    await invoke("send_files", { path: path }).then((res) => {
-      setTicket(res as string);
-    });
+    await invoke("send_files", { path: path });
   };
 
+  useEffect(() => {
+    const unlisten = listen("share_ready", (event) => {
+      const { ticket, error } = event.payload as any;
+      if (ticket) {
+        setTicket(ticket);
+      }
+      if (error) {
+        setConnected(false);
+      }
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
   return (
     <main className="px-20 py-20 flex flex-col gap-10 relative w-full">
       <div className="absolute top-5 right-5">
@@ -56,7 +56,8 @@ export const DragAndDrop = () => {
 
   useEffect(() => {
     const unlisten = listen("upload_progress", (event) => {
-      const { progress, total } = event.payload as any;
+      const { progress, total, files_found, files_hashed } =
+        event.payload as any;
       setProgress(progress);
       setTotal(total);
 
@@ -66,7 +67,7 @@ export const DragAndDrop = () => {
       }
 
       // Reset state when upload is complete
-      if (progress >= total) {
+      if (total > 0 && progress >= total && files_hashed === files_found) {
         setTimeout(() => {
           setIsUploading(false);
           setProgress(0);
@@ -99,7 +100,8 @@ export const DragAndDrop = () => {
     };
   }, []);
 
-  const percent = Math.min(100, (progress / total) * 100).toFixed(2);
+  const percent =
+    total > 0 ? Math.min(100, (progress / total) * 100).toFixed(2) : "0";
 
   return (
     <div className="relative overflow-hidden">
@@ -1,5 +1,5 @@
 import { invoke } from "@tauri-apps/api/core";
-import { useEffect, useState } from "react";
+import { useEffect, useRef, useState } from "react";
 import { open } from "@tauri-apps/plugin-dialog";
 import { listen } from "@tauri-apps/api/event";
 import { AnimatePresence, motion } from "framer-motion";
@@ -10,6 +10,8 @@ export const Receive = () => {
   const [isDownloading, setIsDownloading] = useState(false);
   const [progress, setProgress] = useState(0);
   const [total, setTotal] = useState(100);
+  // progress events of other receives carry a different id
+  const receiveId = useRef<number | null>(null);
 
   async function receive(e: React.FormEvent) {
     e.preventDefault();
@@ -22,26 +24,36 @@ export const Receive = () => {
     setIsDownloading(true);
     if (!filePath) return;
 
-    await invoke("receive_files", { ticket: ticket, path: filePath });
+    receiveId.current = await invoke<number>("receive_files", {
+      ticket: ticket,
+      path: filePath,
+    });
   }
 
   useEffect(() => {
     const unlisten = listen("download_progress", (event) => {
-      const { progress, total } = event.payload as any;
+      const { id, progress, total } = event.payload as any;
+      if (id !== receiveId.current) return;
       setProgress(progress);
       setTotal(total);
+    });
 
-      if (progress >= total) {
-        setTimeout(() => setIsDownloading(false), 500);
-      }
+    // downloading and exporting each run up to 100%, so only the end of
+    // the receive hides the progress bar
+    const unlistenFinished = listen("receive_finished", (event) => {
+      if ((event.payload as any).id !== receiveId.current) return;
+      setTimeout(() => setIsDownloading(false), 500);
     });
 
     return () => {
       unlisten.then((f) => f());
+      unlistenFinished.then((f) => f());
     };
   }, []);
 
-  const percent = Math.min(100, (progress / total) * 100).toFixed(2);
+  // the total is not known yet while connecting
+  const percent =
+    total > 0 ? Math.min(100, (progress / total) * 100).toFixed(2) : "0";
 
   return (
     <motion.div>